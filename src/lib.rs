@@ -3,78 +3,1072 @@
 
 use colored::*;
 use indexmap::{IndexMap, IndexSet};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::fmt;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
 use std::sync::Arc;
-use std::sync::RwLock;
+use std::sync::{OnceLock, RwLock};
+use std::thread::ThreadId;
 use std::time::{Duration, Instant};
 
 #[derive(Clone, Debug)]
 struct BenchResult {
-    list: Vec<Duration>,
+    // The durations of the samples and their weights.
+    list: Vec<(Duration, u32)>,
+    // The number of items and the time taken to process them.
+    items: Option<(u64, Duration)>,
+    // The number of warmup samples skipped.
+    skipped: usize,
+    // The max number of samples to retain.
+    cap: Option<(usize, Retention)>,
+    // The number of samples recorded including the ones not retained.
+    seen: u64,
+    // The position of the oldest sample in the list when the latest samples are retained.
+    head: usize,
+    rng: u64,
 }
 impl BenchResult {
     fn new() -> Self {
-        Self { list: vec![] }
+        Self {
+            list: vec![],
+            items: None,
+            skipped: 0,
+            cap: None,
+            seen: 0,
+            head: 0,
+            rng: 0,
+        }
+    }
+    fn with_cap(cap: Option<(usize, Retention)>) -> Self {
+        let mut br = Self::new();
+        br.set_cap(cap);
+        br
     }
     fn n(&self) -> usize {
         self.list.len()
     }
+    /// The sum of the weights of the samples.
+    fn weight(&self) -> u64 {
+        self.list.iter().map(|&(_, w)| w as u64).sum()
+    }
+    fn title(&self, sw_tag: &str) -> String {
+        let samples = if self.seen as usize > self.n() {
+            format!("{} of {} samples", self.n(), self.seen)
+        } else {
+            format!("{} samples", self.n())
+        };
+        if self.skipped > 0 {
+            format!("{} ({}, {} warmup skipped)", sw_tag, samples, self.skipped)
+        } else {
+            format!("{} ({})", sw_tag, samples)
+        }
+    }
+    /// The retained samples and their weights in the recorded order.
+    /// The order is lost if the samples are retained by reservoir sampling.
+    fn ordered(&self) -> Vec<(Duration, u32)> {
+        let mut list = self.list.clone();
+        list.rotate_left(self.head);
+        list
+    }
+    /// The retained samples per weight in the recorded order.
+    fn samples(&self) -> Vec<Duration> {
+        self.ordered().into_iter().map(|(du, w)| du / w).collect()
+    }
+    // The samples per weight and their weights.
+    fn values(&self) -> impl Iterator<Item = (Duration, u64)> + '_ {
+        self.list.iter().map(|&(du, w)| (du / w, w as u64))
+    }
+    // The sorted samples per weight with the cumulative weights up to them.
+    fn sorted(&self) -> Vec<(Duration, u64)> {
+        let mut list: Vec<_> = self.values().collect();
+        list.sort();
+        let mut cum = 0;
+        for (_, w) in &mut list {
+            cum += *w;
+            *w = cum;
+        }
+        list
+    }
+    // The k-th (0-based) of the sorted samples as if each sample is repeated by its weight.
+    fn nth(sorted: &[(Duration, u64)], k: u64) -> Duration {
+        let i = sorted.partition_point(|&(_, cum)| cum <= k);
+        sorted[i].0
+    }
+    fn set_cap(&mut self, cap: Option<(usize, Retention)>) {
+        self.list = self.ordered();
+        self.head = 0;
+        self.cap = cap;
+        if self.rng == 0 {
+            use std::hash::{BuildHasher, Hasher};
+            let seed = std::collections::hash_map::RandomState::new()
+                .build_hasher()
+                .finish();
+            self.rng = seed | 1;
+        }
+        self.trim();
+    }
+    // Drop the samples over the cap.
+    fn trim(&mut self) {
+        if let Some((cap, retention)) = self.cap {
+            match retention {
+                Retention::Latest => {
+                    let over = self.list.len().saturating_sub(cap);
+                    self.list.drain(..over);
+                }
+                Retention::Reservoir => {
+                    while self.list.len() > cap {
+                        let i = (self.next_rand() % self.list.len() as u64) as usize;
+                        self.list.swap_remove(i);
+                    }
+                }
+            }
+        }
+    }
+    // xorshift64*
+    fn next_rand(&mut self) -> u64 {
+        let mut x = self.rng;
+        x ^= x >> 12;
+        x ^= x << 25;
+        x ^= x >> 27;
+        self.rng = x;
+        x.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
     fn add_result(&mut self, du: Duration) {
-        self.list.push(du);
+        self.add_weighted(du, 1);
+    }
+    fn add_weighted(&mut self, du: Duration, weight: u32) {
+        self.seen += 1;
+        let sample = (du, weight);
+        match self.cap {
+            Some((cap, retention)) if self.list.len() >= cap => match retention {
+                Retention::Latest => {
+                    self.list[self.head] = sample;
+                    self.head = (self.head + 1) % cap;
+                }
+                Retention::Reservoir => {
+                    let i = (self.next_rand() % self.seen) as usize;
+                    if i < cap {
+                        self.list[i] = sample;
+                    }
+                }
+            },
+            _ => self.list.push(sample),
+        }
+    }
+    fn add_items(&mut self, du: Duration, n: u64) {
+        self.add_result(du);
+        let (items, time) = self.items.get_or_insert((0, Duration::ZERO));
+        *items += n;
+        *time += du;
+    }
+    fn merge(&mut self, other: &BenchResult) {
+        let mut list = self.ordered();
+        let mut other_list = other.ordered();
+        self.head = 0;
+        match self.cap {
+            Some((cap, Retention::Reservoir)) if list.len() + other_list.len() > cap => {
+                // Each retained sample stands for seen / retained samples of its side.
+                let weight = |seen: u64, list: &[(Duration, u32)]| {
+                    if list.is_empty() {
+                        0.
+                    } else {
+                        seen.max(list.len() as u64) as f64
+                    }
+                };
+                let mut sides = [
+                    (weight(self.seen, &list), list),
+                    (weight(other.seen, &other_list), other_list),
+                ];
+                let mut out = vec![];
+                while out.len() < cap {
+                    let x = (self.next_rand() >> 11) as f64 / (1u64 << 53) as f64;
+                    let i = if x * (sides[0].0 + sides[1].0) < sides[0].0 {
+                        0
+                    } else {
+                        1
+                    };
+                    let (w, side) = &mut sides[i];
+                    let j = (self.next_rand() % side.len() as u64) as usize;
+                    *w -= *w / side.len() as f64;
+                    out.push(side.swap_remove(j));
+                }
+                self.list = out;
+            }
+            _ => {
+                list.append(&mut other_list);
+                self.list = list;
+                self.trim();
+            }
+        }
+        self.seen += other.seen;
+        if let Some((n, du)) = other.items {
+            let (items, time) = self.items.get_or_insert((0, Duration::ZERO));
+            *items += n;
+            *time += du;
+        }
+        self.skipped += other.skipped;
+    }
+    /// The number of items processed per second.
+    fn throughput(&self) -> Option<f64> {
+        let (items, time) = self.items?;
+        Some(items as f64 / time.as_secs_f64())
+    }
+    /// The total time divided by the total weight.
+    fn average(&self) -> Option<Duration> {
+        let w = self.weight();
+        if w == 0 {
+            return None;
+        }
+        Some(Duration::from_nanos(
+            (self.total().as_nanos() / w as u128) as u64,
+        ))
     }
-    fn average(&self) -> Duration {
-        let n = self.list.len();
+    /// The geometric mean of the samples.
+    /// It is zero if any of the samples is zero.
+    fn geometric_mean(&self) -> Option<Duration> {
+        geometric_mean(self.values())
+    }
+    fn total(&self) -> Duration {
         let mut sum = Duration::from_secs(0);
-        for &du in &self.list {
+        for &(du, _) in &self.list {
             sum += du;
         }
-        sum / (n as u32)
+        sum
     }
-    fn percentile(&self, p: u64) -> Duration {
-        assert!(p > 0);
-        let mut list = self.list.clone();
-        list.sort();
-        let p = p as f64 / 100.;
-        let n = self.list.len() as f64;
-        let i = f64::ceil(p * n) as usize;
-        list[i - 1]
+    fn min(&self) -> Option<Duration> {
+        self.values().map(|(du, _)| du).min()
+    }
+    fn max(&self) -> Option<Duration> {
+        self.values().map(|(du, _)| du).max()
+    }
+    // The weighted sum of the squared deviations from the average.
+    fn squared_deviation(&self) -> Option<f64> {
+        let mean = self.average()?.as_secs_f64();
+        let mut sum = 0.;
+        for (du, w) in self.values() {
+            let d = du.as_secs_f64() - mean;
+            sum += w as f64 * d * d;
+        }
+        Some(sum)
+    }
+    /// Population standard deviation of the samples weighted by their weights.
+    fn stddev(&self) -> Option<Duration> {
+        let sum = self.squared_deviation()?;
+        Some(Duration::from_secs_f64(f64::sqrt(
+            sum / self.weight() as f64,
+        )))
+    }
+    /// Confidence interval of the average at the confidence level (e.g. 0.95).
+    ///
+    /// It is computed by Student's t-distribution with the sample standard deviation
+    /// assuming the samples are independent and roughly normally distributed.
+    /// For skewed samples it is only an approximation.
+    /// There must be at least two samples.
+    /// The lower bound is clamped at zero.
+    fn mean_confidence_interval(&self, confidence: f64) -> Option<(Duration, Duration)> {
+        let mean = self.average()?.as_secs_f64();
+        let margin = self.mean_margin(confidence)?.as_secs_f64();
+        let lo = Duration::from_secs_f64(f64::max(mean - margin, 0.));
+        let hi = Duration::from_secs_f64(mean + margin);
+        Some((lo, hi))
+    }
+    /// The half width of the confidence interval of the average.
+    fn mean_margin(&self, confidence: f64) -> Option<Duration> {
+        assert!(
+            0. < confidence && confidence < 1.,
+            "confidence must be in (0, 1)"
+        );
+        // A weighted sample is a single observation so the degrees of freedom are by the samples.
+        let n = self.n();
+        if n < 2 {
+            return None;
+        }
+        let variance = self.squared_deviation()? / self.weight() as f64;
+        let s = f64::sqrt(variance * n as f64 / (n - 1) as f64);
+        let t = student_t_quantile((1. + confidence) / 2., (n - 1) as f64);
+        Some(Duration::from_secs_f64(t * s / f64::sqrt(n as f64)))
+    }
+    /// Coefficient of variation: the ratio of the standard deviation to the average.
+    fn cv(&self) -> Option<f64> {
+        Some(self.stddev()?.as_secs_f64() / self.average()?.as_secs_f64())
+    }
+    /// The median computed by linear interpolation.
+    fn median(&self) -> Option<Duration> {
+        self.percentile_with(50., PercentileMethod::Linear)
+    }
+    /// The interquartile range (p75 - p25) computed by linear interpolation.
+    fn iqr(&self) -> Option<Duration> {
+        let p25 = self.percentile_with(25., PercentileMethod::Linear)?;
+        let p75 = self.percentile_with(75., PercentileMethod::Linear)?;
+        Some(p75 - p25)
+    }
+    /// The average of the samples without the `trim_fraction` of the smallest ones
+    /// and the same fraction of the largest ones by weight.
+    fn trimmed_mean(&self, trim_fraction: f64) -> Option<Duration> {
+        assert!(
+            (0. ..0.5).contains(&trim_fraction),
+            "trim_fraction must be in [0, 0.5)"
+        );
+        let n = self.weight();
+        if n == 0 {
+            return None;
+        }
+        let k = f64::floor(n as f64 * trim_fraction) as u64;
+        let mut sum = 0;
+        let mut start = 0;
+        for (du, cum) in self.sorted() {
+            // The repetitions of the sample within [k, n - k).
+            let m = u64::min(cum, n - k).saturating_sub(u64::max(start, k));
+            sum += du.as_nanos() * m as u128;
+            start = cum;
+        }
+        Some(Duration::from_nanos((sum / (n - 2 * k) as u128) as u64))
+    }
+    /// The samples farther than 1.5 IQR below p25 or above p75.
+    fn outliers(&self) -> Vec<Duration> {
+        let (p25, p75) = match (
+            self.percentile_with(25., PercentileMethod::Linear),
+            self.percentile_with(75., PercentileMethod::Linear),
+        ) {
+            (Some(p25), Some(p75)) => (p25.as_secs_f64(), p75.as_secs_f64()),
+            _ => return vec![],
+        };
+        let d = 1.5 * (p75 - p25);
+        let mut out = vec![];
+        for du in self.samples() {
+            let x = du.as_secs_f64();
+            if x < p25 - d || x > p75 + d {
+                out.push(du);
+            }
+        }
+        out
+    }
+    /// Count the samples by weight into `bins` buckets spanning from the min to the max.
+    /// Each bucket is returned with its lower bound.
+    ///
+    /// If all the samples are the same, there is only one bucket.
+    fn histogram(&self, bins: usize, scale: HistogramScale) -> Vec<(Duration, usize)> {
+        assert!(bins > 0, "bins must be positive");
+        let (min, max) = match (self.min(), self.max()) {
+            (Some(min), Some(max)) => (min, max),
+            _ => return vec![],
+        };
+        if min == max {
+            return vec![(min, self.weight() as usize)];
+        }
+        // Map a duration to the axis where the buckets are equally spaced.
+        let axis = |du: Duration| -> f64 {
+            match scale {
+                HistogramScale::Linear => du.as_secs_f64(),
+                // Avoid log(0) by regarding zero as 1ns.
+                HistogramScale::Log => f64::ln(f64::max(du.as_secs_f64(), 1e-9)),
+            }
+        };
+        let lo = axis(min);
+        let width = (axis(max) - lo) / bins as f64;
+        let mut out = vec![];
+        for i in 0..bins {
+            let x = lo + i as f64 * width;
+            let bound = match scale {
+                HistogramScale::Linear => x,
+                HistogramScale::Log => f64::exp(x),
+            };
+            out.push((Duration::from_secs_f64(bound), 0));
+        }
+        out[0].0 = min;
+        for (du, w) in self.values() {
+            let i = ((axis(du) - lo) / width) as usize;
+            out[usize::min(i, bins - 1)].1 += w as usize;
+        }
+        out
+    }
+    fn percentile(&self, p: f64) -> Option<Duration> {
+        self.percentile_with(p, PercentileMethod::NearestRank)
+    }
+    /// The percentile as if each sample is repeated by its weight.
+    fn percentile_with(&self, p: f64, method: PercentileMethod) -> Option<Duration> {
+        debug_assert!(PercentileError::check(p).is_ok());
+        if self.list.is_empty() {
+            return None;
+        }
+        let sorted = self.sorted();
+        let n = self.weight();
+        match method {
+            PercentileMethod::NearestRank => {
+                let i = f64::ceil(p * n as f64 / 100.) as u64;
+                Some(Self::nth(&sorted, i - 1))
+            }
+            PercentileMethod::Linear => {
+                let h = (n - 1) as f64 * p / 100.;
+                let lo = f64::floor(h) as u64;
+                let hi = u64::min(lo + 1, n - 1);
+                let lo_v = Self::nth(&sorted, lo).as_secs_f64();
+                let hi_v = Self::nth(&sorted, hi).as_secs_f64();
+                let v = lo_v + (h - lo as f64) * (hi_v - lo_v);
+                Some(Duration::from_secs_f64(v))
+            }
+        }
+    }
+}
+/// How to retain the samples of a tag over the cap set by [`BenchMan::set_max_samples`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Retention {
+    /// Keep the latest samples in a ring buffer.
+    /// The statistics only reflect the recent samples.
+    Latest,
+    /// Keep a uniform random subset of all the samples by reservoir sampling.
+    /// The statistics estimate the whole run without bias but the recorded order is lost.
+    /// A merge keeps the samples of each side in proportion to the samples it has seen.
+    Reservoir,
+}
+/// Scale of the histogram buckets.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum HistogramScale {
+    /// The buckets have the same width.
+    #[default]
+    Linear,
+    /// The buckets have the same width in the logarithmic scale.
+    Log,
+}
+const HISTOGRAM_WIDTH: usize = 40;
+/// Order of the tags in the output.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SortOrder {
+    /// The order the tags are first used.
+    #[default]
+    Insertion,
+    /// The slowest average first.
+    MeanDesc,
+    /// The fastest average first.
+    MeanAsc,
+    /// The most samples first.
+    SamplesDesc,
+}
+impl SortOrder {
+    // The tags without samples go last in any order.
+    fn sort<T>(&self, results: &mut [(T, &BenchResult)]) {
+        match self {
+            SortOrder::Insertion => {}
+            SortOrder::MeanDesc => results.sort_by_key(|(_, br)| std::cmp::Reverse(br.average())),
+            SortOrder::MeanAsc => results.sort_by_key(|(_, br)| (br.n() == 0, br.average())),
+            SortOrder::SamplesDesc => results.sort_by_key(|(_, br)| std::cmp::Reverse(br.n())),
+        }
+    }
+}
+/// Method to compute percentiles.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PercentileMethod {
+    /// The smallest sample such that at least p% of the samples are less than or equal to it.
+    #[default]
+    NearestRank,
+    /// Linear interpolation between the closest ranks.
+    /// This is known as type 7 and the same as Excel's `PERCENTILE.INC`.
+    Linear,
+}
+/// The error of a percentile out of (0, 100].
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PercentileError(pub f64);
+impl PercentileError {
+    fn check(p: f64) -> Result<(), Self> {
+        if 0. < p && p <= 100. {
+            Ok(())
+        } else {
+            Err(Self(p))
+        }
+    }
+}
+impl fmt::Display for PercentileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "percentile {} is out of (0, 100]", self.0)
     }
 }
+impl std::error::Error for PercentileError {}
 impl fmt::Display for BenchResult {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        let p50 = self.percentile(50);
-        let p95 = self.percentile(95);
-        let p99 = self.percentile(99);
-        writeln!(f, "[ave.] {:?}", self.average())?;
-        writeln!(f, "{:?} (>50%), {:?} (>95%), {:?} (>99%)", p50, p95, p99)?;
+        write!(f, "{}", Config::default().view(self))
+    }
+}
+/// Display of a result by the config.
+struct ResultView<'a> {
+    result: &'a BenchResult,
+    config: &'a Config,
+}
+impl<'a> fmt::Display for ResultView<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let br = self.result;
+        if br.n() == 0 {
+            writeln!(f, "(no samples)")?;
+            return Ok(());
+        }
+        match self.config.trim_fraction {
+            Some(trim_fraction) => writeln!(
+                f,
+                "[ave.] {} [trimmed ave.] {}",
+                format_duration(br.average().unwrap()),
+                format_duration(br.trimmed_mean(trim_fraction).unwrap())
+            )?,
+            None => writeln!(f, "[ave.] {}", format_duration(br.average().unwrap()))?,
+        }
+        if let Some(confidence) = self.config.confidence {
+            if let Some(margin) = br.mean_margin(confidence) {
+                writeln!(
+                    f,
+                    "[{}% CI] {} ± {}",
+                    format_level(confidence),
+                    format_duration(br.average().unwrap()),
+                    format_duration(margin)
+                )?;
+            }
+        }
+        writeln!(
+            f,
+            "[min] {} [max] {}",
+            format_duration(br.min().unwrap()),
+            format_duration(br.max().unwrap())
+        )?;
+        writeln!(
+            f,
+            "[total] {} across {} samples",
+            format_duration(br.total()),
+            br.n()
+        )?;
+        writeln!(
+            f,
+            "[stddev] {} [cv] {:.3}",
+            format_duration(br.stddev().unwrap()),
+            br.cv().unwrap()
+        )?;
+        writeln!(
+            f,
+            "[median] {} [iqr] {}",
+            format_duration(br.median().unwrap()),
+            format_duration(br.iqr().unwrap())
+        )?;
+        let mut ps = vec![];
+        for &p in &self.config.percentiles {
+            ps.push(format!(
+                "{} (>{}%)",
+                format_duration(br.percentile(p).unwrap()),
+                p
+            ));
+        }
+        writeln!(f, "{}", ps.join(", "))?;
+        if let Some(throughput) = br.throughput() {
+            writeln!(f, "[throughput] {} ops/s", format_si(throughput))?;
+        }
+        if let Some((bins, scale)) = self.config.histogram {
+            let histogram = br.histogram(bins, scale);
+            let max_count = histogram.iter().map(|&(_, c)| c).max().unwrap();
+            for (bound, count) in histogram {
+                let bar = "▇".repeat(count * HISTOGRAM_WIDTH / max_count);
+                writeln!(f, "{} {} {}", format_duration(bound), bar, count)?;
+            }
+        }
         Ok(())
     }
 }
-#[derive(Debug)]
+/// The q-quantile (q >= 0.5) of Student's t-distribution with v degrees of freedom.
+fn student_t_quantile(q: f64, v: f64) -> f64 {
+    // The CDF of t >= 0.
+    let cdf = |t: f64| 1. - 0.5 * beta_inc(v / 2., 0.5, v / (v + t * t));
+    let mut hi = 1.;
+    while cdf(hi) < q {
+        hi *= 2.;
+    }
+    let mut lo = 0.;
+    for _ in 0..100 {
+        let mid = (lo + hi) / 2.;
+        if cdf(mid) < q {
+            lo = mid;
+        } else {
+            hi = mid;
+        }
+    }
+    (lo + hi) / 2.
+}
+/// The regularized incomplete beta function I_x(a, b).
+fn beta_inc(a: f64, b: f64, x: f64) -> f64 {
+    if x <= 0. {
+        return 0.;
+    }
+    if x >= 1. {
+        return 1.;
+    }
+    let ln_bt = ln_gamma(a + b) - ln_gamma(a) - ln_gamma(b) + a * x.ln() + b * (1. - x).ln();
+    let bt = ln_bt.exp();
+    // The continued fraction converges fast on this side.
+    if x < (a + 1.) / (a + b + 2.) {
+        bt * beta_cf(a, b, x) / a
+    } else {
+        1. - bt * beta_cf(b, a, 1. - x) / b
+    }
+}
+/// The continued fraction of the incomplete beta function by the modified Lentz's method.
+fn beta_cf(a: f64, b: f64, x: f64) -> f64 {
+    const TINY: f64 = 1e-300;
+    let mut c = 1.;
+    let mut d = 1. - (a + b) * x / (a + 1.);
+    if d.abs() < TINY {
+        d = TINY;
+    }
+    d = 1. / d;
+    let mut h = d;
+    for m in 1..300 {
+        let m = m as f64;
+        let m2 = 2. * m;
+        for aa in [
+            m * (b - m) * x / ((a + m2 - 1.) * (a + m2)),
+            -(a + m) * (a + b + m) * x / ((a + m2) * (a + m2 + 1.)),
+        ] {
+            d = 1. + aa * d;
+            if d.abs() < TINY {
+                d = TINY;
+            }
+            c = 1. + aa / c;
+            if c.abs() < TINY {
+                c = TINY;
+            }
+            d = 1. / d;
+            h *= d * c;
+        }
+        if (d * c - 1.).abs() < 1e-15 {
+            break;
+        }
+    }
+    h
+}
+/// ln Γ(x) for x >= 0.5 by the Lanczos approximation.
+fn ln_gamma(x: f64) -> f64 {
+    const G: f64 = 7.;
+    const COEFS: [f64; 9] = [
+        0.999_999_999_999_809_9,
+        676.520_368_121_885_1,
+        -1_259.139_216_722_402_8,
+        771.323_428_777_653_1,
+        -176.615_029_162_140_6,
+        12.507_343_278_686_905,
+        -0.138_571_095_265_720_12,
+        9.984_369_578_019_572e-6,
+        1.505_632_735_149_311_6e-7,
+    ];
+    let x = x - 1.;
+    let mut a = COEFS[0];
+    for (i, &c) in COEFS.iter().enumerate().skip(1) {
+        a += c / (x + i as f64);
+    }
+    let t = x + G + 0.5;
+    0.5 * (2. * std::f64::consts::PI).ln() + (x + 0.5) * t.ln() - t + a.ln()
+}
+/// The geometric mean of the durations weighted by their weights.
+fn geometric_mean(list: impl IntoIterator<Item = (Duration, u64)>) -> Option<Duration> {
+    let mut sum = 0.;
+    let mut n = 0;
+    for (du, w) in list {
+        // log(0) is -inf so the mean is zero.
+        if du.is_zero() {
+            return Some(Duration::ZERO);
+        }
+        sum += w as f64 * f64::ln(du.as_secs_f64());
+        n += w;
+    }
+    if n == 0 {
+        return None;
+    }
+    Some(Duration::from_secs_f64(f64::exp(sum / n as f64)))
+}
+/// Format the duration in 3 significant figures with a unit (e.g. `1.23 ms`, `987 µs`, `2.10 s`).
+///
+/// The largest unit is second.
+pub fn format_duration(du: Duration) -> String {
+    let ns = du.as_nanos();
+    if ns < 1000 {
+        return format!("{} ns", ns);
+    }
+    let ns = ns as f64;
+    let units = [(1e3, "µs"), (1e6, "ms"), (1e9, "s")];
+    for (i, &(scale, unit)) in units.iter().enumerate() {
+        let s = format_sig3(ns / scale);
+        // The rounding can carry up to the next unit (e.g. 999.9 µs to 1.00 ms).
+        let carried = s.parse::<f64>().unwrap() >= 1000.;
+        if !carried || i == units.len() - 1 {
+            return format!("{} {}", s, unit);
+        }
+    }
+    unreachable!()
+}
+/// Format the confidence level in percent with the fewest digits that don't round it to 100.
+fn format_level(confidence: f64) -> String {
+    let x = confidence * 100.;
+    let mut d = 1;
+    let mut s = format!("{:.*}", d, x);
+    while s.parse::<f64>().unwrap() >= 100. && d < 17 {
+        d += 1;
+        s = format!("{:.*}", d, x);
+    }
+    s.trim_end_matches('0').trim_end_matches('.').to_owned()
+}
+fn format_sig3(x: f64) -> String {
+    let mut d = if x >= 100. {
+        0
+    } else if x >= 10. {
+        1
+    } else {
+        2
+    };
+    let mut s = format!("{:.*}", d, x);
+    // The rounding can add a digit (e.g. 9.999 to 10.00).
+    if d > 0 && s.parse::<f64>().unwrap() >= f64::powi(10., 3 - d as i32) {
+        d -= 1;
+        s = format!("{:.*}", d, x);
+    }
+    s
+}
+fn format_si(x: f64) -> String {
+    if x >= 1e9 {
+        format!("{:.2}G", x / 1e9)
+    } else if x >= 1e6 {
+        format!("{:.2}M", x / 1e6)
+    } else if x >= 1e3 {
+        format!("{:.2}k", x / 1e3)
+    } else {
+        format!("{:.2}", x)
+    }
+}
+/// Statistics of the samples collected under a stopwatch tag.
+///
+/// Only tags with at least one sample have statistics.
+#[derive(Clone, Debug)]
+pub struct Stats {
+    /// The number of samples.
+    pub samples: usize,
+    /// The average of the samples.
+    pub average: Duration,
+    result: BenchResult,
+    overhead: Duration,
+}
+impl Stats {
+    fn new(result: BenchResult, overhead: Duration) -> Option<Self> {
+        Some(Self {
+            samples: result.n(),
+            average: result.average()?,
+            result,
+            overhead,
+        })
+    }
+    /// Get the average minus the stopwatch overhead estimated by [`BenchMan::calibrate`].
+    ///
+    /// It is the same as the average if the benchman isn't calibrated.
+    pub fn average_adjusted(&self) -> Duration {
+        self.average.saturating_sub(self.overhead)
+    }
+    /// Get the p-th percentile of the samples.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `p` isn't in (0, 100]. Use [`Stats::try_percentile`] to handle it.
+    pub fn percentile(&self, p: f64) -> Duration {
+        self.try_percentile(p).unwrap_or_else(|e| panic!("{}", e))
+    }
+    /// Get the p-th percentile of the samples or an error if `p` isn't in (0, 100].
+    pub fn try_percentile(&self, p: f64) -> Result<Duration, PercentileError> {
+        self.try_percentile_with(p, PercentileMethod::NearestRank)
+    }
+    /// Get the confidence interval of the average at the confidence level (e.g. 0.95).
+    ///
+    /// It is computed by Student's t-distribution assuming the samples are independent
+    /// and roughly normally distributed, which one-shot samples may not be.
+    /// A sample added by [`BenchMan::add_weighted`] is a single observation of the average per weight.
+    /// It is `None` if there is only one sample.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `confidence` isn't in (0, 1).
+    pub fn mean_confidence_interval(&self, confidence: f64) -> Option<(Duration, Duration)> {
+        self.result.mean_confidence_interval(confidence)
+    }
+    /// Get the sum of the samples.
+    pub fn total(&self) -> Duration {
+        self.result.total()
+    }
+    /// Get the geometric mean of the samples. It is zero if any of the samples is zero.
+    pub fn geometric_mean(&self) -> Duration {
+        self.result.geometric_mean().unwrap()
+    }
+    /// Get the average without the `trim_fraction` of the smallest and the largest samples.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `trim_fraction` isn't in [0, 0.5).
+    pub fn trimmed_mean(&self, trim_fraction: f64) -> Duration {
+        self.result.trimmed_mean(trim_fraction).unwrap()
+    }
+    /// Get the samples farther than 1.5 IQR below p25 or above p75 in the recorded order.
+    pub fn outliers(&self) -> Vec<Duration> {
+        self.result.outliers()
+    }
+    /// Get the histogram of the samples in `bins` buckets spanning from the min to the max.
+    ///
+    /// Each bucket is returned with its lower bound.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `bins` is zero.
+    pub fn histogram(&self, bins: usize, scale: HistogramScale) -> Vec<(Duration, usize)> {
+        self.result.histogram(bins, scale)
+    }
+    /// Get the p-th percentile of the samples computed by the method.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `p` isn't in (0, 100]. Use [`Stats::try_percentile_with`] to handle it.
+    pub fn percentile_with(&self, p: f64, method: PercentileMethod) -> Duration {
+        self.try_percentile_with(p, method)
+            .unwrap_or_else(|e| panic!("{}", e))
+    }
+    /// Get the p-th percentile of the samples computed by the method
+    /// or an error if `p` isn't in (0, 100].
+    pub fn try_percentile_with(
+        &self,
+        p: f64,
+        method: PercentileMethod,
+    ) -> Result<Duration, PercentileError> {
+        PercentileError::check(p)?;
+        Ok(self.result.percentile_with(p, method).unwrap())
+    }
+}
+#[cfg(feature = "serde")]
+impl serde::Serialize for Stats {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::SerializeStruct;
+        let mut st = serializer.serialize_struct("Stats", 5)?;
+        st.serialize_field("samples", &self.samples)?;
+        st.serialize_field("average_ns", &(self.average.as_nanos() as u64))?;
+        st.serialize_field("p50_ns", &(self.percentile(50.).as_nanos() as u64))?;
+        st.serialize_field("p95_ns", &(self.percentile(95.).as_nanos() as u64))?;
+        st.serialize_field("p99_ns", &(self.percentile(99.).as_nanos() as u64))?;
+        st.end()
+    }
+}
+/// The file format of [`BenchMan::save`].
+#[cfg(feature = "serde")]
+#[derive(serde::Serialize, serde::Deserialize)]
+struct SavedBenchMan {
+    tag: String,
+    results: Vec<SavedResult>,
+    #[serde(default)]
+    counters: Vec<(String, u64)>,
+    #[serde(default)]
+    overhead_ns: u64,
+}
+#[cfg(feature = "serde")]
+#[derive(serde::Serialize, serde::Deserialize)]
+struct SavedResult {
+    tag: String,
+    samples_ns: Vec<u64>,
+    // The number of items and the time in nanoseconds.
+    items: Option<(u64, u64)>,
+    skipped: usize,
+    #[serde(default)]
+    parent: Option<String>,
+    // The number of samples recorded including the ones not retained.
+    #[serde(default)]
+    seen: u64,
+    #[serde(default)]
+    cap: Option<(usize, Retention)>,
+    // The weights of the samples. Empty if all the weights are 1.
+    #[serde(default)]
+    weights: Vec<u32>,
+}
+/// Summary of the benchman.
+///
+/// The stopwatch tags are ordered as they are first used.
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct Summary {
+    /// The tag of the benchman.
+    pub tag: String,
+    /// The statistics keyed by the stopwatch tags.
+    pub stats: IndexMap<String, Stats>,
+}
+#[derive(Clone, Debug)]
 struct ResultSet {
     tag_indices: IndexSet<String>,
     h: HashMap<String, BenchResult>,
+    // The number of warmup samples yet to skip.
+    warmup: HashMap<String, usize>,
+    // The results per thread. Collected only if per_thread is true.
+    per_thread: bool,
+    threads: HashMap<String, IndexMap<ThreadId, BenchResult>>,
+    // The counters in the order they are first used.
+    counters: IndexMap<String, u64>,
+    // The overhead of a stopwatch estimated by calibration.
+    overhead: Duration,
+    // The parent tag of the child tag.
+    parents: HashMap<String, String>,
+    // The max number of samples to retain per tag.
+    caps: HashMap<String, (usize, Retention)>,
 }
 impl ResultSet {
     fn new() -> Self {
         Self {
             tag_indices: IndexSet::new(),
             h: HashMap::new(),
+            warmup: HashMap::new(),
+            per_thread: false,
+            threads: HashMap::new(),
+            counters: IndexMap::new(),
+            overhead: Duration::ZERO,
+            parents: HashMap::new(),
+            caps: HashMap::new(),
         }
     }
     fn reserve_tag(&mut self, tag: String) {
         self.tag_indices.insert(tag);
     }
-    fn add_result(&mut self, tag: String, du: Duration) {
-        self.h
-            .entry(tag)
-            .or_insert(BenchResult::new())
-            .add_result(du);
+    fn add_result(&mut self, tag: String, du: Duration, items: Option<u64>) {
+        self.add_sample(tag, du, 1, items);
+    }
+    fn add_weighted(&mut self, tag: String, du: Duration, weight: u32) {
+        self.add_sample(tag, du, weight, None);
+    }
+    fn add_sample(&mut self, tag: String, du: Duration, weight: u32, items: Option<u64>) {
+        let add = |br: &mut BenchResult| match items {
+            Some(n) => br.add_items(du, n),
+            None => br.add_weighted(du, weight),
+        };
+        let cap = self.caps.get(&tag).copied();
+        let br = self
+            .h
+            .entry(tag.clone())
+            .or_insert_with(|| BenchResult::with_cap(cap));
+        if let Some(warmup) = self.warmup.get_mut(&tag) {
+            if *warmup > 0 {
+                *warmup -= 1;
+                br.skipped += 1;
+                return;
+            }
+        }
+        add(br);
+        if self.per_thread {
+            let br = self
+                .threads
+                .entry(tag)
+                .or_default()
+                .entry(std::thread::current().id())
+                .or_insert_with(|| BenchResult::with_cap(cap));
+            add(br);
+        }
+    }
+    fn merge(&mut self, other: &ResultSet) {
+        for tag in &other.tag_indices {
+            self.tag_indices.insert(tag.clone());
+        }
+        for (tag, br) in &other.h {
+            let cap = self.caps.get(tag).copied();
+            self.h
+                .entry(tag.clone())
+                .or_insert_with(|| BenchResult::with_cap(cap))
+                .merge(br);
+        }
+        for (tag, threads) in &other.threads {
+            let self_threads = self.threads.entry(tag.clone()).or_default();
+            let cap = self.caps.get(tag).copied();
+            for (&id, br) in threads {
+                self_threads
+                    .entry(id)
+                    .or_insert_with(|| BenchResult::with_cap(cap))
+                    .merge(br);
+            }
+        }
+        for (tag, &n) in &other.counters {
+            *self.counters.entry(tag.clone()).or_insert(0) += n;
+        }
+        for (tag, parent) in &other.parents {
+            self.parents.insert(tag.clone(), parent.clone());
+        }
+    }
+}
+#[derive(Clone, Debug)]
+struct Config {
+    colored: bool,
+    percentiles: Vec<f64>,
+    trim_fraction: Option<f64>,
+    histogram: Option<(usize, HistogramScale)>,
+    sort: SortOrder,
+    filter: Option<Vec<String>>,
+    confidence: Option<f64>,
+}
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            colored: true,
+            percentiles: vec![50., 95., 99.],
+            trim_fraction: None,
+            histogram: None,
+            sort: SortOrder::Insertion,
+            filter: None,
+            confidence: None,
+        }
+    }
+}
+impl Config {
+    fn filter(&self, sw_tag: &str) -> bool {
+        match &self.filter {
+            Some(patterns) => patterns.iter().any(|pat| glob_match(pat, sw_tag)),
+            None => true,
+        }
+    }
+    fn view<'a>(&'a self, result: &'a BenchResult) -> ResultView<'a> {
+        ResultView {
+            result,
+            config: self,
+        }
+    }
+    fn paint(&self, s: &str, color: Color) -> ColoredString {
+        if self.colored {
+            s.color(color)
+        } else {
+            s.normal()
+        }
+    }
+}
+/// Match the string against the pattern where `*` matches any sequence of characters.
+fn glob_match(pattern: &str, s: &str) -> bool {
+    let p: Vec<char> = pattern.chars().collect();
+    let s: Vec<char> = s.chars().collect();
+    let (mut i, mut j) = (0, 0);
+    // The position of the last `*` in the pattern and where it started to match in the string.
+    let mut star: Option<(usize, usize)> = None;
+    while j < s.len() {
+        if i < p.len() && p[i] == '*' {
+            star = Some((i, j));
+            i += 1;
+        } else if i < p.len() && p[i] == s[j] {
+            i += 1;
+            j += 1;
+        } else if let Some((si, sj)) = star {
+            // Let the last `*` match one more character.
+            i = si + 1;
+            j = sj + 1;
+            star = Some((si, sj + 1));
+        } else {
+            return false;
+        }
     }
+    p[i..].iter().all(|&c| c == '*')
 }
+const CALIBRATION_ITERATIONS: usize = 1000;
 /// Benchman who collects the result from stopwatches.
 ///
+/// The stopwatches write their results into the benchman directly under a lock.
+/// No sample is ever dropped: under contention, a dropping stopwatch waits for the lock.
+///
+/// Both benchman and stopwatch are `Send` and `Sync`, and the clones of a benchman share the result.
+/// A benchman spawns no thread and the result is freed when the last clone and stopwatch are dropped.
+///
 /// ```rust
 /// use benchman::*;
 /// let bm = BenchMan::new("bm_tag");
@@ -88,6 +1082,7 @@ impl ResultSet {
 pub struct BenchMan {
     tag: Arc<String>,
     result_set: Arc<RwLock<ResultSet>>,
+    config: Arc<RwLock<Config>>,
 }
 impl BenchMan {
     /// Create a benchman.
@@ -96,89 +1091,1000 @@ impl BenchMan {
         Self {
             tag: Arc::new(tag.to_owned()),
             result_set,
+            config: Arc::new(RwLock::new(Config::default())),
         }
     }
-    /// Get a stopwatch from benchman.
-    pub fn get_stopwatch(&self, tag: &str) -> Stopwatch {
-        self.result_set.write().unwrap().reserve_tag(tag.to_owned());
-        Stopwatch::new(tag.to_owned(), self.result_set.clone())
+    /// Enable or disable the colored output. (default: true)
+    ///
+    /// Even if enabled, the color is suppressed when `NO_COLOR` is set in the environment.
+    pub fn set_colored(&self, colored: bool) {
+        self.config.write().unwrap().colored = colored;
     }
-    /// Get an immutable view of the benchman.
+    /// Set the percentiles to print. (default: 50, 95, 99)
     ///
-    /// If a tag in the list isn't found in the current result, the tag is ignored.
-    pub fn slice<'a>(&'a self, sw_tags: impl IntoIterator<Item = &'a str>) -> BenchManSlice<'a> {
-        let result_set_reader = &self.result_set.read().unwrap();
-        let mut m = IndexMap::new();
-        for sw_tag in sw_tags {
-            if let Some(br) = result_set_reader.h.get(sw_tag) {
-                m.insert(sw_tag, br.clone());
+    /// # Panics
+    ///
+    /// Panics if any percentile isn't in (0, 100].
+    pub fn set_percentiles(&self, percentiles: &[f64]) {
+        for &p in percentiles {
+            if let Err(e) = PercentileError::check(p) {
+                panic!("{}", e);
             }
         }
-        BenchManSlice {
-            bm_tag: &self.tag,
-            slices: m,
-        }
+        self.config.write().unwrap().percentiles = percentiles.to_vec();
     }
-}
-impl fmt::Display for BenchMan {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        let bench_tag = &self.tag;
-        writeln!(f, "{}", bench_tag.blue())?;
-        let result_set_reader = &self.result_set.read().unwrap();
-        for sw_tag in &result_set_reader.tag_indices {
-            if let Some(v) = result_set_reader.h.get(sw_tag) {
-                let tag = format!("{} ({} samples)", sw_tag, v.n());
-                writeln!(f, "{}", tag.yellow())?;
-                writeln!(f, "{}", v)?;
-            }
+    /// Print the trimmed mean without the `trim_fraction` of the smallest and the largest samples
+    /// alongside the average. (default: None)
+    ///
+    /// # Panics
+    ///
+    /// Panics if `trim_fraction` isn't in [0, 0.5).
+    pub fn set_trimmed_mean(&self, trim_fraction: Option<f64>) {
+        if let Some(trim_fraction) = trim_fraction {
+            assert!(
+                (0. ..0.5).contains(&trim_fraction),
+                "trim_fraction must be in [0, 0.5)"
+            );
         }
-        Ok(())
+        self.config.write().unwrap().trim_fraction = trim_fraction;
     }
-}
-/// Immutable view of the benchman.
-pub struct BenchManSlice<'a> {
-    bm_tag: &'a str,
-    slices: IndexMap<&'a str, BenchResult>,
-}
-impl<'a> fmt::Display for BenchManSlice<'a> {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        let bench_tag = &self.bm_tag;
-        writeln!(f, "{}", bench_tag.blue())?;
-        for (sw_tag, br) in &self.slices {
-            let tag = format!("{} ({} samples)", sw_tag, br.n());
-            writeln!(f, "{}", tag.yellow())?;
-            writeln!(f, "{}", br)?;
+    /// Print the histogram of `bins` buckets for each tag. (default: None)
+    ///
+    /// # Panics
+    ///
+    /// Panics if `bins` is zero.
+    pub fn set_histogram(&self, bins: Option<usize>, scale: HistogramScale) {
+        if let Some(bins) = bins {
+            assert!(bins > 0, "bins must be positive");
         }
-        Ok(())
+        self.config.write().unwrap().histogram = bins.map(|bins| (bins, scale));
     }
-}
-
-/// On drop, it sends a result to the benchman.
-pub struct Stopwatch {
-    tag: Option<String>,
-    t: Instant,
-    result_set: Arc<RwLock<ResultSet>>,
-}
-impl Stopwatch {
-    fn new(tag: String, result_set: Arc<RwLock<ResultSet>>) -> Self {
-        Self {
-            tag: Some(tag),
-            t: Instant::now(),
-            result_set,
-        }
+    /// Set the order of the tags to print. (default: Insertion)
+    ///
+    /// The slice is always printed in the order of the given tags.
+    pub fn set_sort(&self, sort: SortOrder) {
+        self.config.write().unwrap().sort = sort;
     }
-}
-impl Drop for Stopwatch {
-    fn drop(&mut self) {
-        let elapsed = self.t.elapsed();
-        let sw_tag = self.tag.take().unwrap();
-        self.result_set.write().unwrap().add_result(sw_tag, elapsed);
+    /// Print only the tags matching any of the patterns. (default: None)
+    ///
+    /// In a pattern, `*` matches any sequence of characters (e.g. `db/*`).
+    pub fn set_filter(&self, patterns: Option<&[&str]>) {
+        let patterns =
+            patterns.map(|patterns| patterns.iter().map(|&pat| pat.to_owned()).collect());
+        self.config.write().unwrap().filter = patterns;
     }
-}
-
-#[cfg(test)]
-mod tests {
-    use super::*;
+    /// Add `n` to the counter of the tag.
+    ///
+    /// The counters are separate from the stopwatch tags and printed after them.
+    pub fn count(&self, tag: &str, n: u64) {
+        *self
+            .result_set
+            .write()
+            .unwrap()
+            .counters
+            .entry(tag.to_owned())
+            .or_insert(0) += n;
+    }
+    /// Add a sample of `weight` operations taking `du` in total
+    /// such as a batch measured by a single stopwatch.
+    ///
+    /// The statistics count the sample as `weight` repetitions of `du / weight`.
+    /// The average is the total time divided by the total weight and
+    /// the p-th percentile is the smallest time per operation such that
+    /// at least p% of the total weight is less than or equal to it.
+    /// It is still a single sample for the warmup, the cap and the number of samples.
+    /// With the `tracing` feature, it emits a `sample` event as a stopwatch does.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `weight` is zero.
+    pub fn add_weighted(&self, tag: &str, du: Duration, weight: u32) {
+        assert!(weight > 0, "weight must be positive");
+        let mut result_set_writer = self.result_set.write().unwrap();
+        result_set_writer.reserve_tag(tag.to_owned());
+        #[cfg(feature = "tracing")]
+        trace_sample(tag, du, weight);
+        result_set_writer.add_weighted(tag.to_owned(), du, weight);
+    }
+    /// Get the total of the counter of the tag.
+    pub fn counter(&self, tag: &str) -> Option<u64> {
+        self.result_set.read().unwrap().counters.get(tag).copied()
+    }
+    /// Estimate the overhead of a stopwatch included in every sample
+    /// and use it for [`Stats::average_adjusted`].
+    ///
+    /// The overhead is the median of the durations measured by 1000 stopwatches
+    /// which measure nothing. They are dropped right after the creation
+    /// and their results are discarded without emitting anything to `tracing`.
+    pub fn calibrate(&self) -> Duration {
+        let result_set = Arc::new(RwLock::new(ResultSet::new()));
+        for _ in 0..CALIBRATION_ITERATIONS {
+            drop(Stopwatch::untraced(
+                "calibration".to_owned(),
+                result_set.clone(),
+            ));
+        }
+        let overhead = result_set.read().unwrap().h["calibration"]
+            .median()
+            .unwrap();
+        self.result_set.write().unwrap().overhead = overhead;
+        overhead
+    }
+    /// Print the confidence interval of the average at the confidence level (e.g. 0.95). (default: None)
+    ///
+    /// # Panics
+    ///
+    /// Panics if `confidence` isn't in (0, 1).
+    pub fn set_confidence_interval(&self, confidence: Option<f64>) {
+        if let Some(confidence) = confidence {
+            assert!(
+                0. < confidence && confidence < 1.,
+                "confidence must be in (0, 1)"
+            );
+        }
+        self.config.write().unwrap().confidence = confidence;
+    }
+    /// Get a stopwatch from benchman.
+    pub fn get_stopwatch(&self, tag: &str) -> Stopwatch {
+        self.result_set.write().unwrap().reserve_tag(tag.to_owned());
+        Stopwatch::new(tag.to_owned(), self.result_set.clone())
+    }
+    /// Merge the result of another benchman into this benchman.
+    ///
+    /// The samples of a tag found in both are concatenated.
+    pub fn merge(&self, other: &BenchMan) {
+        let other_result_set = other.result_set.read().unwrap().clone();
+        self.result_set.write().unwrap().merge(&other_result_set);
+    }
+    /// Skip the first `n` samples of the tag as warmup.
+    pub fn set_warmup(&self, tag: &str, n: usize) {
+        self.result_set
+            .write()
+            .unwrap()
+            .warmup
+            .insert(tag.to_owned(), n);
+    }
+    /// Retain at most `cap` samples of the tag.
+    ///
+    /// The samples already recorded over the cap are dropped.
+    /// The other statistics like the throughput still account all the samples.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `cap` is zero.
+    pub fn set_max_samples(&self, tag: &str, cap: usize, retention: Retention) {
+        assert!(cap > 0, "cap must be positive");
+        let mut result_set_writer = self.result_set.write().unwrap();
+        let result_set = &mut *result_set_writer;
+        result_set.caps.insert(tag.to_owned(), (cap, retention));
+        if let Some(br) = result_set.h.get_mut(tag) {
+            br.set_cap(Some((cap, retention)));
+        }
+        if let Some(threads) = result_set.threads.get_mut(tag) {
+            for br in threads.values_mut() {
+                br.set_cap(Some((cap, retention)));
+            }
+        }
+    }
+    /// Clear the result of every tag and every counter.
+    ///
+    /// The tags are kept so they are printed in the same order as before.
+    pub fn clear(&self) {
+        let mut result_set_writer = self.result_set.write().unwrap();
+        result_set_writer.h.clear();
+        result_set_writer.threads.clear();
+        result_set_writer.counters.clear();
+    }
+    /// Clear the result of the stopwatch tag.
+    ///
+    /// A counter of the same name is kept. Use [`BenchMan::clear_counter`] to clear it.
+    pub fn clear_tag(&self, tag: &str) {
+        let mut result_set_writer = self.result_set.write().unwrap();
+        result_set_writer.h.remove(tag);
+        result_set_writer.threads.remove(tag);
+    }
+    /// Clear the counter of the tag.
+    pub fn clear_counter(&self, tag: &str) {
+        self.result_set.write().unwrap().counters.shift_remove(tag);
+    }
+    /// Collect and print the results per thread as well. (default: false)
+    ///
+    /// Only the samples recorded after enabling it are attributed to the threads.
+    /// The results per thread aren't saved by [`BenchMan::save`].
+    pub fn set_per_thread(&self, per_thread: bool) {
+        self.result_set.write().unwrap().per_thread = per_thread;
+    }
+    /// Get the statistics of the tag per thread in the order the threads are first seen.
+    pub fn by_thread(&self, tag: &str) -> Vec<(ThreadId, Stats)> {
+        let result_set_reader = &self.result_set.read().unwrap();
+        let mut out = vec![];
+        if let Some(threads) = result_set_reader.threads.get(tag) {
+            for (&id, br) in threads {
+                if let Some(stats) = Stats::new(br.clone(), result_set_reader.overhead) {
+                    out.push((id, stats));
+                }
+            }
+        }
+        out
+    }
+    /// Measure the closure and return its value.
+    ///
+    /// If the closure panics, nothing is recorded.
+    pub fn measure<T>(&self, tag: &str, f: impl FnOnce() -> T) -> T {
+        let mut guard = CancelGuard(Some(self.get_stopwatch(tag)));
+        let x = f();
+        guard.0.take().unwrap().stop();
+        x
+    }
+    /// Measure the closure and return its value.
+    ///
+    /// The result is recorded only if the closure returns `Ok`.
+    pub fn try_measure<T, E>(&self, tag: &str, f: impl FnOnce() -> Result<T, E>) -> Result<T, E> {
+        let mut guard = CancelGuard(Some(self.get_stopwatch(tag)));
+        let x = f();
+        if x.is_ok() {
+            guard.0.take().unwrap().stop();
+        }
+        x
+    }
+    /// Compare the averages against the baseline for each tag found in both.
+    /// A tag whose average is zero in the baseline is skipped because the change is undefined.
+    ///
+    /// A change of more than 5% is regarded as slower or faster.
+    /// Use [`ComparisonReport::with_threshold`] to change it.
+    pub fn compare(&self, baseline: &BenchMan) -> ComparisonReport {
+        let baseline_summary = baseline.summary();
+        let current_summary = self.summary();
+        let mut comparisons = vec![];
+        for (sw_tag, current) in &current_summary.stats {
+            if let Some(base) = baseline_summary.stats.get(sw_tag) {
+                if base.average.is_zero() {
+                    continue;
+                }
+                let change = (current.average.as_secs_f64() - base.average.as_secs_f64())
+                    / base.average.as_secs_f64();
+                comparisons.push(Comparison {
+                    tag: sw_tag.clone(),
+                    baseline: base.average,
+                    current: current.average,
+                    change,
+                });
+            }
+        }
+        ComparisonReport {
+            bm_tag: current_summary.tag,
+            baseline_tag: baseline_summary.tag,
+            threshold: 0.05,
+            comparisons,
+            config: self.config.read().unwrap().clone(),
+        }
+    }
+    /// Save the result to the file in JSON.
+    #[cfg(feature = "serde")]
+    pub fn save<P: AsRef<std::path::Path>>(&self, path: P) -> io::Result<()> {
+        let result_set_reader = &self.result_set.read().unwrap();
+        let mut results = vec![];
+        for sw_tag in &result_set_reader.tag_indices {
+            let mut saved = SavedResult {
+                tag: sw_tag.clone(),
+                samples_ns: vec![],
+                items: None,
+                skipped: 0,
+                parent: result_set_reader.parents.get(sw_tag).cloned(),
+                seen: 0,
+                cap: result_set_reader.caps.get(sw_tag).copied(),
+                weights: vec![],
+            };
+            if let Some(br) = result_set_reader.h.get(sw_tag) {
+                let list = br.ordered();
+                saved.samples_ns = list.iter().map(|(du, _)| du.as_nanos() as u64).collect();
+                if list.iter().any(|&(_, w)| w != 1) {
+                    saved.weights = list.iter().map(|&(_, w)| w).collect();
+                }
+                saved.items = br.items.map(|(n, du)| (n, du.as_nanos() as u64));
+                saved.skipped = br.skipped;
+                saved.seen = br.seen;
+            }
+            results.push(saved);
+        }
+        let saved = SavedBenchMan {
+            tag: self.tag.to_string(),
+            results,
+            counters: result_set_reader
+                .counters
+                .iter()
+                .map(|(tag, &n)| (tag.clone(), n))
+                .collect(),
+            overhead_ns: result_set_reader.overhead.as_nanos() as u64,
+        };
+        let mut w = io::BufWriter::new(std::fs::File::create(path)?);
+        serde_json::to_writer(&mut w, &saved)?;
+        io::Write::flush(&mut w)?;
+        Ok(())
+    }
+    /// Load the result saved by [`BenchMan::save`].
+    ///
+    /// The loaded benchman is a usual one so it can be compared, printed or even measure more.
+    #[cfg(feature = "serde")]
+    pub fn load<P: AsRef<std::path::Path>>(path: P) -> io::Result<BenchMan> {
+        let r = io::BufReader::new(std::fs::File::open(path)?);
+        let saved: SavedBenchMan = serde_json::from_reader(r)?;
+        let bm = BenchMan::new(&saved.tag);
+        {
+            let mut result_set_writer = bm.result_set.write().unwrap();
+            for saved in saved.results {
+                result_set_writer.reserve_tag(saved.tag.clone());
+                if let Some(parent) = saved.parent {
+                    result_set_writer.parents.insert(saved.tag.clone(), parent);
+                }
+                if let Some(cap) = saved.cap {
+                    result_set_writer.caps.insert(saved.tag.clone(), cap);
+                }
+                if saved.samples_ns.is_empty() && saved.items.is_none() && saved.skipped == 0 {
+                    continue;
+                }
+                let mut br = BenchResult::new();
+                let weights = saved.weights.into_iter().chain(std::iter::repeat(1));
+                br.list = saved
+                    .samples_ns
+                    .into_iter()
+                    .map(Duration::from_nanos)
+                    .zip(weights)
+                    .collect();
+                br.seen = u64::max(saved.seen, br.list.len() as u64);
+                br.set_cap(saved.cap);
+                br.items = saved.items.map(|(n, ns)| (n, Duration::from_nanos(ns)));
+                br.skipped = saved.skipped;
+                result_set_writer.h.insert(saved.tag, br);
+            }
+            result_set_writer.counters = saved.counters.into_iter().collect();
+            result_set_writer.overhead = Duration::from_nanos(saved.overhead_ns);
+        }
+        Ok(bm)
+    }
+    /// Measure the future from the first poll to the completion and return its output.
+    ///
+    /// If the future is dropped before the completion, nothing is recorded.
+    pub fn measure_async<F: Future>(&self, tag: &str, fut: F) -> impl Future<Output = F::Output> {
+        Measure::new(self.clone(), tag, fut, false)
+    }
+    /// Measure only the time spent in polling the future and return its output.
+    ///
+    /// If the future is dropped before the completion, nothing is recorded.
+    pub fn measure_async_polls<F: Future>(
+        &self,
+        tag: &str,
+        fut: F,
+    ) -> impl Future<Output = F::Output> {
+        Measure::new(self.clone(), tag, fut, true)
+    }
+    /// Get an immutable view of the benchman.
+    ///
+    /// If a tag in the list isn't found in the current result, the tag is ignored.
+    pub fn slice<'a>(&'a self, sw_tags: impl IntoIterator<Item = &'a str>) -> BenchManSlice<'a> {
+        let result_set_reader = &self.result_set.read().unwrap();
+        let mut m = IndexMap::new();
+        for sw_tag in sw_tags {
+            if let Some(br) = result_set_reader.h.get(sw_tag) {
+                m.insert(sw_tag, br.clone());
+            }
+        }
+        BenchManSlice {
+            bm_tag: &self.tag,
+            slices: m,
+            config: self.config.read().unwrap().clone(),
+        }
+    }
+    /// Get the statistics of the current result keyed by the stopwatch tags.
+    pub fn snapshot(&self) -> BTreeMap<String, Stats> {
+        let result_set_reader = &self.result_set.read().unwrap();
+        let mut m = BTreeMap::new();
+        for (sw_tag, br) in &result_set_reader.h {
+            if let Some(stats) = Stats::new(br.clone(), result_set_reader.overhead) {
+                m.insert(sw_tag.clone(), stats);
+            }
+        }
+        m
+    }
+    /// Get the number of samples of the tag.
+    pub fn sample_count(&self, tag: &str) -> usize {
+        let result_set_reader = &self.result_set.read().unwrap();
+        result_set_reader.h.get(tag).map_or(0, |br| br.n())
+    }
+    /// Check if the tag has any sample.
+    pub fn has_samples(&self, tag: &str) -> bool {
+        self.sample_count(tag) > 0
+    }
+    /// Get the samples of the tag in the recorded order.
+    ///
+    /// A sample added by [`BenchMan::add_weighted`] is returned as the time per weight.
+    /// It returns an empty list if the tag is used but has no sample yet
+    /// and `None` if the tag is never used.
+    pub fn samples(&self, tag: &str) -> Option<Vec<Duration>> {
+        let result_set_reader = &self.result_set.read().unwrap();
+        match result_set_reader.h.get(tag) {
+            Some(br) => Some(br.samples()),
+            None if result_set_reader.tag_indices.contains(tag) => Some(vec![]),
+            None => None,
+        }
+    }
+    /// Get the geometric mean of the averages of the tags as a single score of the benchman.
+    ///
+    /// It is zero if any of the averages is zero and `None` if there is no sample.
+    pub fn overall_geomean(&self) -> Option<Duration> {
+        let result_set_reader = &self.result_set.read().unwrap();
+        let mut averages = vec![];
+        for br in result_set_reader.h.values() {
+            if let Some(average) = br.average() {
+                averages.push((average, 1));
+            }
+        }
+        geometric_mean(averages)
+    }
+    /// Get the summary of the current result.
+    pub fn summary(&self) -> Summary {
+        let result_set_reader = &self.result_set.read().unwrap();
+        let mut m = IndexMap::new();
+        for sw_tag in &result_set_reader.tag_indices {
+            if let Some(stats) = result_set_reader
+                .h
+                .get(sw_tag)
+                .and_then(|br| Stats::new(br.clone(), result_set_reader.overhead))
+            {
+                m.insert(sw_tag.clone(), stats);
+            }
+        }
+        Summary {
+            tag: self.tag.to_string(),
+            stats: m,
+        }
+    }
+    /// Write the current result in CSV.
+    ///
+    /// Each row is a stopwatch tag in the order it is first used and the durations are in nanoseconds.
+    pub fn to_csv<W: io::Write>(&self, mut w: W) -> io::Result<()> {
+        writeln!(w, "tag,samples,avg_ns,p50_ns,p95_ns,p99_ns,min_ns,max_ns")?;
+        let result_set_reader = &self.result_set.read().unwrap();
+        for sw_tag in &result_set_reader.tag_indices {
+            let br = match result_set_reader.h.get(sw_tag) {
+                Some(br) if br.n() > 0 => br,
+                _ => continue,
+            };
+            let ns = |du: Option<Duration>| du.unwrap().as_nanos();
+            writeln!(
+                w,
+                "{},{},{},{},{},{},{},{}",
+                csv_escape(sw_tag),
+                br.n(),
+                ns(br.average()),
+                ns(br.percentile(50.)),
+                ns(br.percentile(95.)),
+                ns(br.percentile(99.)),
+                ns(br.min()),
+                ns(br.max()),
+            )?;
+        }
+        Ok(())
+    }
+    /// Get the current result as a GitHub-flavored Markdown table.
+    ///
+    /// Each row is a stopwatch tag in the order it is first used.
+    pub fn to_markdown(&self) -> String {
+        let mut out = String::new();
+        out.push_str("| tag | samples | mean | p50 | p95 | p99 |\n");
+        out.push_str("|---|---:|---:|---:|---:|---:|\n");
+        let result_set_reader = &self.result_set.read().unwrap();
+        for sw_tag in &result_set_reader.tag_indices {
+            let br = match result_set_reader.h.get(sw_tag) {
+                Some(br) if br.n() > 0 => br,
+                _ => continue,
+            };
+            let fmt = |du: Option<Duration>| format_duration(du.unwrap());
+            out.push_str(&format!(
+                "| {} | {} | {} | {} | {} | {} |\n",
+                sw_tag.replace('|', "\\|"),
+                br.n(),
+                fmt(br.average()),
+                fmt(br.percentile(50.)),
+                fmt(br.percentile(95.)),
+                fmt(br.percentile(99.)),
+            ));
+        }
+        out
+    }
+    /// Get the current result formatted the same as [`Display`](fmt::Display).
+    ///
+    /// The results of the dropped stopwatches are already in the benchman
+    /// so it returns immediately without waiting for anything.
+    pub fn report(&self) -> String {
+        self.to_string()
+    }
+}
+fn csv_escape(s: &str) -> String {
+    if s.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_owned()
+    }
+}
+impl fmt::Display for BenchMan {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let config = self.config.read().unwrap();
+        let bench_tag = &self.tag;
+        writeln!(f, "{}", config.paint(bench_tag, Color::Blue))?;
+        let result_set_reader = &self.result_set.read().unwrap();
+        let tree = Tree {
+            result_set: result_set_reader,
+            config: &config,
+        };
+        let mut roots = vec![];
+        for sw_tag in &result_set_reader.tag_indices {
+            let is_root = match result_set_reader.parents.get(sw_tag) {
+                Some(parent) => !tree.visible(parent),
+                None => true,
+            };
+            if is_root && tree.visible(sw_tag) {
+                roots.push(sw_tag);
+            }
+        }
+        tree.fmt(f, roots, None, 0)?;
+        for (tag, n) in &result_set_reader.counters {
+            if !config.filter(tag) {
+                continue;
+            }
+            let line = format!("{}: {} (total)", tag, n);
+            writeln!(f, "{}", config.paint(&line, Color::Cyan))?;
+        }
+        Ok(())
+    }
+}
+/// Display of the tags where the children are put under the parents.
+struct Tree<'a> {
+    result_set: &'a ResultSet,
+    config: &'a Config,
+}
+impl<'a> Tree<'a> {
+    fn visible(&self, sw_tag: &str) -> bool {
+        self.config.filter(sw_tag) && self.result_set.h.contains_key(sw_tag)
+    }
+    fn fmt(
+        &self,
+        f: &mut fmt::Formatter,
+        sw_tags: Vec<&String>,
+        parent: Option<(&str, &BenchResult)>,
+        depth: usize,
+    ) -> fmt::Result {
+        let config = self.config;
+        let result_set = self.result_set;
+        let indent = "  ".repeat(depth);
+        let mut results = vec![];
+        for sw_tag in sw_tags {
+            results.push((sw_tag, &result_set.h[sw_tag]));
+        }
+        config.sort.sort(&mut results);
+        let mut children_total = Duration::ZERO;
+        for (sw_tag, v) in results {
+            children_total += v.total();
+            let mut tag = v.title(sw_tag);
+            if let Some((parent_tag, parent)) = parent {
+                if let Some(share) = share(v.total(), parent.total()) {
+                    tag = format!("{} [{:.1}% of {}]", tag, share, parent_tag);
+                }
+            }
+            writeln!(f, "{}{}", indent, config.paint(&tag, Color::Yellow))?;
+            let mut block = config.view(v).to_string();
+            if let Some(threads) = result_set.threads.get(sw_tag) {
+                for (id, br) in threads {
+                    if let Some(ave) = br.average() {
+                        let ave = format_duration(ave);
+                        block += &format!("  {:?} ({} samples) [ave.] {}\n", id, br.n(), ave);
+                    }
+                }
+            }
+            for line in block.lines() {
+                writeln!(f, "{}{}", indent, line)?;
+            }
+            writeln!(f)?;
+            let mut children = vec![];
+            for child in &result_set.tag_indices {
+                if result_set.parents.get(child) == Some(sw_tag) && self.visible(child) {
+                    children.push(child);
+                }
+            }
+            if !children.is_empty() {
+                self.fmt(f, children, Some((sw_tag, v)), depth + 1)?;
+            }
+        }
+        if let Some((parent_tag, parent)) = parent {
+            let unaccounted = parent.total().saturating_sub(children_total);
+            if let Some(share) = share(unaccounted, parent.total()) {
+                writeln!(
+                    f,
+                    "{}(unaccounted) {} [{:.1}% of {}]",
+                    indent,
+                    format_duration(unaccounted),
+                    share,
+                    parent_tag
+                )?;
+                writeln!(f)?;
+            }
+        }
+        Ok(())
+    }
+}
+/// The percentage of the part in the whole.
+fn share(part: Duration, whole: Duration) -> Option<f64> {
+    if whole.is_zero() {
+        return None;
+    }
+    Some(part.as_secs_f64() / whole.as_secs_f64() * 100.)
+}
+/// Immutable view of the benchman.
+pub struct BenchManSlice<'a> {
+    bm_tag: &'a str,
+    slices: IndexMap<&'a str, BenchResult>,
+    config: Config,
+}
+impl<'a> fmt::Display for BenchManSlice<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let config = &self.config;
+        let bench_tag = &self.bm_tag;
+        writeln!(f, "{}", config.paint(bench_tag, Color::Blue))?;
+        for (sw_tag, br) in &self.slices {
+            let tag = br.title(sw_tag);
+            writeln!(f, "{}", config.paint(&tag, Color::Yellow))?;
+            writeln!(f, "{}", config.view(br))?;
+        }
+        Ok(())
+    }
+}
+/// Comparison of the average of a tag against the baseline.
+#[derive(Clone, Debug)]
+pub struct Comparison {
+    /// The stopwatch tag.
+    pub tag: String,
+    /// The average in the baseline.
+    pub baseline: Duration,
+    /// The current average.
+    pub current: Duration,
+    /// The relative change of the average. (e.g. 0.1 for 10% slower)
+    pub change: f64,
+}
+/// Result of comparing a benchman against the baseline.
+pub struct ComparisonReport {
+    bm_tag: String,
+    baseline_tag: String,
+    threshold: f64,
+    comparisons: Vec<Comparison>,
+    config: Config,
+}
+impl ComparisonReport {
+    /// Set the threshold of the relative change to be regarded as slower or faster. (default: 0.05)
+    pub fn with_threshold(mut self, threshold: f64) -> Self {
+        self.threshold = threshold;
+        self
+    }
+    /// Get the comparisons in the order the tags are first used.
+    pub fn comparisons(&self) -> &[Comparison] {
+        &self.comparisons
+    }
+    /// Get the comparisons of the tags slower than the threshold.
+    pub fn regressions(&self) -> Vec<&Comparison> {
+        self.comparisons
+            .iter()
+            .filter(|c| c.change > self.threshold)
+            .collect()
+    }
+}
+impl fmt::Display for ComparisonReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let config = &self.config;
+        let title = format!("{} (baseline: {})", self.bm_tag, self.baseline_tag);
+        writeln!(f, "{}", config.paint(&title, Color::Blue))?;
+        for c in &self.comparisons {
+            let line = format!("{}: {:+.1}%", c.tag, c.change * 100.);
+            if c.change > self.threshold {
+                writeln!(
+                    f,
+                    "{}",
+                    config.paint(&format!("{} (slower)", line), Color::Red)
+                )?;
+            } else if c.change < -self.threshold {
+                writeln!(
+                    f,
+                    "{}",
+                    config.paint(&format!("{} (faster)", line), Color::Green)
+                )?;
+            } else {
+                writeln!(f, "{}", line)?;
+            }
+        }
+        Ok(())
+    }
+}
+/// Future measured by a stopwatch.
+struct Measure<F> {
+    bm: BenchMan,
+    tag: String,
+    fut: Pin<Box<F>>,
+    sw: Option<Stopwatch>,
+    // If true, the stopwatch is paused while the future isn't polled.
+    polls_only: bool,
+}
+impl<F> Measure<F> {
+    fn new(bm: BenchMan, tag: &str, fut: F, polls_only: bool) -> Self {
+        Self {
+            bm,
+            tag: tag.to_owned(),
+            fut: Box::pin(fut),
+            sw: None,
+            polls_only,
+        }
+    }
+}
+impl<F: Future> Future for Measure<F> {
+    type Output = F::Output;
+    fn poll(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Self::Output> {
+        let this = &mut *self;
+        let sw = this
+            .sw
+            .get_or_insert_with(|| this.bm.get_stopwatch(&this.tag));
+        sw.resume();
+        let poll = this.fut.as_mut().poll(cx);
+        if this.polls_only {
+            sw.pause();
+        }
+        if poll.is_ready() {
+            this.sw.take().unwrap().stop();
+        }
+        poll
+    }
+}
+impl<F> Drop for Measure<F> {
+    fn drop(&mut self) {
+        if let Some(sw) = self.sw.take() {
+            sw.cancel();
+        }
+    }
+}
+/// Stopwatch cancelled on drop unless it is taken out and stopped.
+struct CancelGuard(Option<Stopwatch>);
+impl Drop for CancelGuard {
+    fn drop(&mut self) {
+        if let Some(sw) = self.0.take() {
+            sw.cancel();
+        }
+    }
+}
+
+/// On drop, it sends a result to the benchman.
+///
+/// The result is written to the benchman before the drop returns
+/// so anything reading the benchman afterwards sees it without waiting.
+///
+/// A stopwatch held across `.await` measures the wall-clock time
+/// including the time the task is not polled.
+/// Use [`BenchMan::measure_async_polls`] to exclude it.
+///
+/// With the `tracing` feature, it opens a `stopwatch` span with the tag for its lifetime
+/// and emits a `sample` event with the tag, `elapsed_ns` and `weight` whenever a result is recorded
+/// including the laps.
+pub struct Stopwatch {
+    tag: Option<String>,
+    // The start of the current active interval. None while paused.
+    t: Option<Instant>,
+    // The sum of the finished active intervals.
+    acc: Duration,
+    result_set: Arc<RwLock<ResultSet>>,
+    #[cfg(feature = "tracing")]
+    span: tracing::Span,
+    // False for the calibration which emits nothing to tracing.
+    #[cfg(feature = "tracing")]
+    traced: bool,
+}
+impl Stopwatch {
+    fn new(tag: String, result_set: Arc<RwLock<ResultSet>>) -> Self {
+        Self {
+            #[cfg(feature = "tracing")]
+            span: tracing::info_span!(target: "benchman", "stopwatch", tag = tag.as_str()),
+            #[cfg(feature = "tracing")]
+            traced: true,
+            tag: Some(tag),
+            t: Some(Instant::now()),
+            acc: Duration::ZERO,
+            result_set,
+        }
+    }
+    // A stopwatch that emits nothing to tracing, used for the calibration.
+    fn untraced(tag: String, result_set: Arc<RwLock<ResultSet>>) -> Self {
+        Self {
+            #[cfg(feature = "tracing")]
+            span: tracing::Span::none(),
+            #[cfg(feature = "tracing")]
+            traced: false,
+            tag: Some(tag),
+            t: Some(Instant::now()),
+            acc: Duration::ZERO,
+            result_set,
+        }
+    }
+    fn elapsed(&self) -> Duration {
+        match self.t {
+            Some(t) => self.acc + t.elapsed(),
+            None => self.acc,
+        }
+    }
+    /// Stop the stopwatch and send the result to the benchman.
+    ///
+    /// The measured duration is returned as well.
+    pub fn stop(mut self) -> Duration {
+        self.record(None).unwrap()
+    }
+    /// Stop the stopwatch and send the result to the benchman
+    /// with the number of items processed during the measurement.
+    ///
+    /// The benchman reports the throughput of the tag from the items.
+    pub fn stop_with_items(mut self, n: u64) -> Duration {
+        self.record(Some(n)).unwrap()
+    }
+    /// Pause the stopwatch. The time while paused isn't measured.
+    ///
+    /// It does nothing if the stopwatch is already paused.
+    pub fn pause(&mut self) {
+        if let Some(t) = self.t.take() {
+            self.acc += t.elapsed();
+        }
+    }
+    /// Resume the paused stopwatch.
+    ///
+    /// It does nothing if the stopwatch is running.
+    pub fn resume(&mut self) {
+        if self.t.is_none() {
+            self.t = Some(Instant::now());
+        }
+    }
+    /// Send the time since the previous lap (or the creation) to the benchman
+    /// under the tag `<sw_tag>/<lap_tag>` and start the next lap.
+    ///
+    /// After laps, the stopwatch itself measures the time since the last lap.
+    pub fn lap(&mut self, lap_tag: &str) {
+        let elapsed = self.elapsed();
+        let sw_tag = match &self.tag {
+            Some(sw_tag) => format!("{}/{}", sw_tag, lap_tag),
+            None => return,
+        };
+        #[cfg(feature = "tracing")]
+        self.trace_sample(&sw_tag, elapsed);
+        let mut result_set_writer = self.result_set.write().unwrap();
+        result_set_writer.reserve_tag(sw_tag.clone());
+        result_set_writer.add_result(sw_tag, elapsed, None);
+        self.acc = Duration::ZERO;
+        if self.t.is_some() {
+            self.t = Some(Instant::now());
+        }
+    }
+    /// Get a child stopwatch tagged `<sw_tag>/<tag>`.
+    ///
+    /// The benchman prints the child under the parent with its share of the parent's total time.
+    pub fn child(&self, tag: &str) -> Stopwatch {
+        let parent = self.tag.clone().unwrap();
+        let sw_tag = format!("{}/{}", parent, tag);
+        let mut result_set_writer = self.result_set.write().unwrap();
+        result_set_writer.reserve_tag(sw_tag.clone());
+        result_set_writer.parents.insert(sw_tag.clone(), parent);
+        drop(result_set_writer);
+        // The span of the child is opened under the span of this stopwatch.
+        #[cfg(feature = "tracing")]
+        let _enter = self.span.enter();
+        Stopwatch::new(sw_tag, self.result_set.clone())
+    }
+    /// Discard the measurement without sending anything to the benchman.
+    pub fn cancel(mut self) {
+        self.tag.take();
+    }
+    // Emit the sample event in the span of the stopwatch.
+    #[cfg(feature = "tracing")]
+    fn trace_sample(&self, tag: &str, du: Duration) {
+        if self.traced {
+            self.span.in_scope(|| trace_sample(tag, du, 1));
+        }
+    }
+    fn record(&mut self, items: Option<u64>) -> Option<Duration> {
+        let elapsed = self.elapsed();
+        let sw_tag = self.tag.take()?;
+        #[cfg(feature = "tracing")]
+        self.trace_sample(&sw_tag, elapsed);
+        self.result_set
+            .write()
+            .unwrap()
+            .add_result(sw_tag, elapsed, items);
+        Some(elapsed)
+    }
+}
+impl Drop for Stopwatch {
+    fn drop(&mut self) {
+        self.record(None);
+    }
+}
+
+/// Emit the event of a recorded sample.
+#[cfg(feature = "tracing")]
+fn trace_sample(tag: &str, du: Duration, weight: u32) {
+    tracing::info!(
+        target: "benchman",
+        tag,
+        elapsed_ns = du.as_nanos() as u64,
+        weight,
+        "sample"
+    );
+}
+
+/// Measure a block or an expression and return its value.
+///
+/// The result is sent even if the block returns early because the stopwatch is dropped on leaving the scope.
+///
+/// ```rust
+/// use benchman::*;
+/// let bm = BenchMan::new("bm_tag");
+/// let sum = bench!(bm, "sw_tag", {
+///     let mut sum = 0;
+///     for i in 1..10 { sum += i; }
+///     sum
+/// });
+/// assert_eq!(sum, 45);
+/// ```
+#[macro_export]
+macro_rules! bench {
+    ($bm:expr, $tag:expr, $body:expr) => {{
+        let _sw = $bm.get_stopwatch($tag);
+        // A block body like `{ x + 1 }` would otherwise be linted as unnecessary braces.
+        #[allow(unused_braces)]
+        let out = $body;
+        out
+    }};
+}
+
+static GLOBAL: OnceLock<BenchMan> = OnceLock::new();
+
+/// Get the global benchman tagged "global".
+///
+/// All the stopwatch tags share the same namespace.
+pub fn global() -> &'static BenchMan {
+    GLOBAL.get_or_init(|| BenchMan::new("global"))
+}
+
+/// Print the global benchman to stderr and clear it.
+pub fn print_global() {
+    let bm = global();
+    eprintln!("{}", bm);
+    bm.clear();
+}
+
+/// [`bench!`] with the global benchman.
+///
+/// ```rust
+/// use benchman::*;
+/// let sum = bench_global!("sw_tag", {
+///     let mut sum = 0;
+///     for i in 1..10 { sum += i; }
+///     sum
+/// });
+/// assert_eq!(sum, 45);
+/// print_global();
+/// ```
+#[macro_export]
+macro_rules! bench_global {
+    ($tag:expr, $($body:tt)+) => {
+        $crate::bench!($crate::global(), $tag, $($body)+)
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
     #[test]
     fn test_benchman_spawn() {
@@ -223,4 +2129,918 @@ mod tests {
         println!("{}", benchman.slice(["inner", "outer"]));
         println!("{}", benchman);
     }
+
+    #[test]
+    fn test_benchman_snapshot() {
+        let benchman = BenchMan::new("snapshot");
+        for _ in 0..10 {
+            let _sw = benchman.get_stopwatch("loop");
+        }
+        let _sw = benchman.get_stopwatch("pending");
+        let snapshot = benchman.snapshot();
+        assert_eq!(snapshot.len(), 1);
+        let stats = &snapshot["loop"];
+        assert_eq!(stats.samples, 10);
+        assert!(stats.percentile(50.) <= stats.percentile(99.));
+    }
+
+    #[test]
+    fn test_stats_try_percentile() {
+        let mut br = BenchResult::new();
+        for ms in 1..=10 {
+            br.add_result(Duration::from_millis(ms));
+        }
+        let stats = Stats::new(br, Duration::ZERO).unwrap();
+        assert_eq!(stats.try_percentile(100.), Ok(Duration::from_millis(10)));
+        for p in [0., -1., 100.1, f64::NAN] {
+            assert!(stats.try_percentile(p).is_err());
+            assert!(stats
+                .try_percentile_with(p, PercentileMethod::Linear)
+                .is_err());
+        }
+        assert_eq!(
+            stats.try_percentile(101.).unwrap_err().to_string(),
+            "percentile 101 is out of (0, 100]"
+        );
+    }
+
+    #[test]
+    fn test_benchman_summary() {
+        let benchman = BenchMan::new("summary");
+        drop(benchman.get_stopwatch("b"));
+        drop(benchman.get_stopwatch("a"));
+        let _sw = benchman.get_stopwatch("c");
+        let summary = benchman.summary();
+        assert_eq!(summary.tag, "summary");
+        let tags: Vec<&str> = summary.stats.keys().map(|k| k.as_str()).collect();
+        assert_eq!(tags, ["b", "a"]);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_benchman_summary_json() {
+        let benchman = BenchMan::new("json");
+        drop(benchman.get_stopwatch("b"));
+        drop(benchman.get_stopwatch("a"));
+        let json = serde_json::to_string(&benchman.summary()).unwrap();
+        assert!(json.starts_with(r#"{"tag":"json","stats":{"b":{"samples":1,"average_ns":"#));
+        assert!(json.find(r#""b":"#).unwrap() < json.find(r#""a":"#).unwrap());
+    }
+
+    #[test]
+    fn test_benchman_to_csv() {
+        let benchman = BenchMan::new("csv");
+        drop(benchman.get_stopwatch("b"));
+        drop(benchman.get_stopwatch("a,\"x\""));
+        let mut buf = vec![];
+        benchman.to_csv(&mut buf).unwrap();
+        let csv = String::from_utf8(buf).unwrap();
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(
+            lines[0],
+            "tag,samples,avg_ns,p50_ns,p95_ns,p99_ns,min_ns,max_ns"
+        );
+        assert!(lines[1].starts_with("b,1,"));
+        assert!(lines[2].starts_with("\"a,\"\"x\"\"\",1,"));
+        assert_eq!(lines[2].split(',').count(), 9);
+    }
+
+    #[test]
+    fn test_stopwatch_stop() {
+        let benchman = BenchMan::new("stop");
+        let sw = benchman.get_stopwatch("sw");
+        std::thread::sleep(Duration::from_millis(10));
+        let du = sw.stop();
+        assert!(du >= Duration::from_millis(10));
+        let stats = &benchman.snapshot()["sw"];
+        assert_eq!(stats.samples, 1);
+        assert_eq!(stats.average, du);
+    }
+
+    #[test]
+    fn test_stopwatch_cancel() {
+        let benchman = BenchMan::new("cancel");
+        drop(benchman.get_stopwatch("sw"));
+        benchman.get_stopwatch("sw").cancel();
+        assert_eq!(benchman.snapshot()["sw"].samples, 1);
+    }
+
+    #[test]
+    fn test_stopwatch_lap() {
+        let benchman = BenchMan::new("lap");
+        let mut sw = benchman.get_stopwatch("sw");
+        sw.lap("a");
+        sw.lap("b");
+        sw.lap("a");
+        drop(sw);
+        let summary = benchman.summary();
+        let tags: Vec<&str> = summary.stats.keys().map(|k| k.as_str()).collect();
+        assert_eq!(tags, ["sw", "sw/a", "sw/b"]);
+        assert_eq!(summary.stats["sw/a"].samples, 2);
+        assert_eq!(summary.stats["sw"].samples, 1);
+    }
+
+    #[test]
+    fn test_stopwatch_pause_resume() {
+        let benchman = BenchMan::new("pause");
+        let mut sw = benchman.get_stopwatch("sw");
+        sw.resume();
+        sw.pause();
+        sw.pause();
+        std::thread::sleep(Duration::from_millis(100));
+        sw.resume();
+        sw.resume();
+        let du = sw.stop();
+        assert!(du < Duration::from_millis(100));
+    }
+
+    #[test]
+    fn test_stopwatch_stop_with_items() {
+        let benchman = BenchMan::new("items");
+        benchman.set_colored(false);
+        let sw = benchman.get_stopwatch("sw");
+        std::thread::sleep(Duration::from_millis(10));
+        sw.stop_with_items(1000);
+        assert!(benchman.to_string().contains("[throughput] "));
+
+        let mut br = BenchResult::new();
+        br.add_items(Duration::from_millis(500), 1000);
+        br.add_items(Duration::from_millis(500), 1000);
+        assert_eq!(br.throughput(), Some(2000.));
+        assert_eq!(format_si(2_340_000.), "2.34M");
+    }
+
+    #[test]
+    fn test_benchman_merge() {
+        let bm1 = BenchMan::new("bm1");
+        drop(bm1.get_stopwatch("a"));
+        drop(bm1.get_stopwatch("b"));
+        let bm2 = BenchMan::new("bm2");
+        drop(bm2.get_stopwatch("c"));
+        drop(bm2.get_stopwatch("a"));
+        bm1.merge(&bm2);
+        bm1.merge(&bm1.clone());
+        let summary = bm1.summary();
+        let tags: Vec<&str> = summary.stats.keys().map(|k| k.as_str()).collect();
+        assert_eq!(tags, ["a", "b", "c"]);
+        assert_eq!(summary.stats["a"].samples, 4);
+        assert_eq!(summary.stats["c"].samples, 2);
+    }
+
+    #[test]
+    fn test_benchman_clear() {
+        let benchman = BenchMan::new("clear");
+        drop(benchman.get_stopwatch("a"));
+        drop(benchman.get_stopwatch("b"));
+        benchman.clear_tag("a");
+        assert_eq!(benchman.snapshot().len(), 1);
+        benchman.clear();
+        assert!(benchman.snapshot().is_empty());
+        drop(benchman.get_stopwatch("b"));
+        drop(benchman.get_stopwatch("a"));
+        let summary = benchman.summary();
+        let tags: Vec<&str> = summary.stats.keys().map(|k| k.as_str()).collect();
+        assert_eq!(tags, ["a", "b"]);
+    }
+
+    #[test]
+    fn test_benchman_warmup() {
+        let benchman = BenchMan::new("warmup");
+        benchman.set_colored(false);
+        benchman.set_warmup("sw", 3);
+        for _ in 0..2 {
+            drop(benchman.get_stopwatch("sw"));
+        }
+        assert!(benchman.snapshot().is_empty());
+        assert!(benchman
+            .to_string()
+            .contains("sw (0 samples, 2 warmup skipped)\n(no samples)\n"));
+        for _ in 0..5 {
+            drop(benchman.get_stopwatch("sw"));
+        }
+        assert_eq!(benchman.snapshot()["sw"].samples, 4);
+        assert!(benchman
+            .to_string()
+            .contains("sw (4 samples, 3 warmup skipped)"));
+    }
+
+    #[test]
+    fn test_benchman_percentiles() {
+        let benchman = BenchMan::new("percentiles");
+        benchman.set_percentiles(&[90., 99.9]);
+        for _ in 0..10 {
+            drop(benchman.get_stopwatch("sw"));
+        }
+        let out = benchman.to_string();
+        assert!(out.contains("(>90%), "));
+        assert!(out.contains("(>99.9%)\n"));
+        assert!(!out.contains("(>50%)"));
+
+        let mut br = BenchResult::new();
+        for ms in 1..=1000 {
+            br.add_result(Duration::from_millis(ms));
+        }
+        assert_eq!(br.percentile(99.9), Some(Duration::from_millis(999)));
+    }
+
+    #[test]
+    fn test_bench_result_percentile_linear() {
+        let mut br = BenchResult::new();
+        for ms in [1, 2, 3, 4, 5, 6, 7] {
+            br.add_result(Duration::from_millis(ms));
+        }
+        let p95 = |method| br.percentile_with(95., method).unwrap();
+        assert_eq!(p95(PercentileMethod::NearestRank), Duration::from_millis(7));
+        let linear = p95(PercentileMethod::Linear).as_secs_f64();
+        assert!((linear - 0.0067).abs() < 1e-9);
+        let p100 = br.percentile_with(100., PercentileMethod::Linear);
+        assert_eq!(p100, Some(Duration::from_millis(7)));
+    }
+
+    #[test]
+    fn test_bench_result_median_iqr() {
+        let mut br = BenchResult::new();
+        for ms in [1, 2, 3, 4, 5, 6, 7, 8] {
+            br.add_result(Duration::from_millis(ms));
+        }
+        assert_eq!(br.median(), Some(Duration::from_micros(4500)));
+        assert_eq!(br.iqr(), Some(Duration::from_micros(3500)));
+    }
+
+    #[test]
+    fn test_bench_result_trimmed_mean_outliers() {
+        let mut br = BenchResult::new();
+        for ms in [10, 11, 9, 10, 1000, 10, 11, 9, 10, 0] {
+            br.add_result(Duration::from_millis(ms));
+        }
+        assert_eq!(br.trimmed_mean(0.1), Some(Duration::from_millis(10)));
+        assert_eq!(br.trimmed_mean(0.), br.average());
+        assert_eq!(
+            br.outliers(),
+            [Duration::from_millis(1000), Duration::from_millis(0)]
+        );
+
+        let benchman = BenchMan::new("trimmed");
+        benchman.set_trimmed_mean(Some(0.1));
+        drop(benchman.get_stopwatch("sw"));
+        assert!(benchman.to_string().contains("[trimmed ave.] "));
+    }
+
+    #[test]
+    fn test_bench_result_histogram() {
+        let mut br = BenchResult::new();
+        for ms in [1, 2, 2, 3, 9, 10] {
+            br.add_result(Duration::from_millis(ms));
+        }
+        let histogram = br.histogram(3, HistogramScale::Linear);
+        let counts: Vec<usize> = histogram.iter().map(|&(_, c)| c).collect();
+        assert_eq!(counts, [4, 0, 2]);
+        assert_eq!(histogram[0].0, Duration::from_millis(1));
+        assert_eq!(histogram[1].0, Duration::from_millis(4));
+
+        let histogram = br.histogram(2, HistogramScale::Log);
+        let counts: Vec<usize> = histogram.iter().map(|&(_, c)| c).collect();
+        assert_eq!(counts, [4, 2]);
+        let sqrt10 = f64::sqrt(10.) / 1000.;
+        assert!((histogram[1].0.as_secs_f64() - sqrt10).abs() < 1e-9);
+
+        let benchman = BenchMan::new("histogram");
+        benchman.set_histogram(Some(4), HistogramScale::Linear);
+        drop(benchman.get_stopwatch("sw"));
+        assert!(benchman
+            .to_string()
+            .contains(" ▇▇▇▇▇▇▇▇▇▇▇▇▇▇▇▇▇▇▇▇▇▇▇▇▇▇▇▇▇▇▇▇▇▇▇▇▇▇▇▇ 1\n"));
+    }
+
+    #[test]
+    fn test_benchman_samples() {
+        let benchman = BenchMan::new("samples");
+        let sw = benchman.get_stopwatch("sw");
+        assert_eq!(benchman.samples("sw"), Some(vec![]));
+        let du = sw.stop();
+        assert_eq!(benchman.samples("sw"), Some(vec![du]));
+        assert_eq!(benchman.samples("unknown"), None);
+    }
+
+    #[test]
+    fn test_bench_macro() {
+        fn parse(bm: &BenchMan, s: &str) -> Result<u64, std::num::ParseIntError> {
+            let n = bench!(bm, "parse", { s.parse::<u64>()? });
+            Ok(n)
+        }
+        let benchman = BenchMan::new("macro");
+        assert_eq!(parse(&benchman, "42"), Ok(42));
+        assert!(parse(&benchman, "x").is_err());
+        assert_eq!(benchman.snapshot()["parse"].samples, 2);
+        let _sw = 0;
+        assert_eq!(bench!(benchman, "expr", _sw + 1), 1);
+        assert_eq!(benchman.snapshot()["expr"].samples, 1);
+    }
+
+    #[test]
+    fn test_benchman_measure() {
+        let benchman = BenchMan::new("measure");
+        assert_eq!(benchman.measure("sw", || 1 + 1), 2);
+        let bm = benchman.clone();
+        let panicked = std::panic::catch_unwind(move || bm.measure("sw", || panic!()));
+        assert!(panicked.is_err());
+        assert_eq!(benchman.try_measure("sw", || Ok::<_, ()>(1)), Ok(1));
+        assert_eq!(benchman.try_measure("sw", || Err::<(), _>(1)), Err(1));
+        let bm = benchman.clone();
+        let panicked = std::panic::catch_unwind(move || {
+            bm.try_measure("sw", || -> Result<(), ()> { panic!() })
+        });
+        assert!(panicked.is_err());
+        assert_eq!(benchman.snapshot()["sw"].samples, 2);
+
+        // A plain stopwatch still records when it is dropped by a panic.
+        let bm = benchman.clone();
+        let panicked = std::panic::catch_unwind(move || {
+            let _sw = bm.get_stopwatch("raii");
+            panic!()
+        });
+        assert!(panicked.is_err());
+        assert_eq!(benchman.sample_count("raii"), 1);
+    }
+
+    #[test]
+    fn test_bench_global() {
+        assert_eq!(bench_global!("test_bench_global", { 1 + 1 }), 2);
+        assert_eq!(global().snapshot()["test_bench_global"].samples, 1);
+    }
+
+    #[test]
+    fn test_benchman_sort() {
+        let benchman = BenchMan::new("sort");
+        benchman.set_colored(false);
+        benchman.set_warmup("empty", 1);
+        drop(benchman.get_stopwatch("empty"));
+        let order = |sort| {
+            benchman.set_sort(sort);
+            let out = benchman.to_string();
+            let mut tags: Vec<_> = ["fast", "slow", "empty"]
+                .into_iter()
+                .map(|tag| (out.find(&format!("\n{} (", tag)).unwrap(), tag))
+                .collect();
+            tags.sort();
+            tags.into_iter().map(|(_, tag)| tag).collect::<Vec<_>>()
+        };
+        drop(benchman.get_stopwatch("fast"));
+        drop(benchman.get_stopwatch("fast"));
+        let sw = benchman.get_stopwatch("slow");
+        std::thread::sleep(Duration::from_millis(10));
+        drop(sw);
+        assert_eq!(order(SortOrder::Insertion), ["empty", "fast", "slow"]);
+        assert_eq!(order(SortOrder::MeanDesc), ["slow", "fast", "empty"]);
+        assert_eq!(order(SortOrder::MeanAsc), ["fast", "slow", "empty"]);
+        assert_eq!(order(SortOrder::SamplesDesc), ["fast", "slow", "empty"]);
+    }
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match("db/*", "db/read"));
+        assert!(glob_match("db/*", "db/"));
+        assert!(!glob_match("db/*", "cache/read"));
+        assert!(glob_match("*/read", "db/read"));
+        assert!(glob_match("a*b*c", "aXbYbZc"));
+        assert!(!glob_match("a*b*c", "aXbY"));
+        assert!(glob_match("read", "read"));
+        assert!(!glob_match("read", "reader"));
+    }
+
+    #[test]
+    fn test_benchman_filter() {
+        let benchman = BenchMan::new("filter");
+        benchman.set_colored(false);
+        drop(benchman.get_stopwatch("db/read"));
+        drop(benchman.get_stopwatch("cache"));
+        benchman.set_filter(Some(&["db/*"]));
+        let out = benchman.to_string();
+        assert!(out.contains("db/read ("));
+        assert!(!out.contains("cache ("));
+        benchman.set_filter(None);
+        assert!(benchman.to_string().contains("cache ("));
+    }
+
+    #[test]
+    fn test_benchman_compare() {
+        let baseline = BenchMan::new("baseline");
+        let current = BenchMan::new("current");
+        baseline.set_colored(false);
+        current.set_colored(false);
+        baseline.add_weighted("sw", Duration::from_millis(10), 1);
+        current.add_weighted("sw", Duration::from_millis(20), 1);
+        baseline.add_weighted("zero", Duration::ZERO, 1);
+        current.add_weighted("zero", Duration::from_millis(1), 1);
+        drop(current.get_stopwatch("new"));
+        let report = current.compare(&baseline);
+        assert_eq!(report.comparisons().len(), 1);
+        assert_eq!(report.comparisons()[0].change, 1.);
+        assert_eq!(report.regressions().len(), 1);
+        let out = report.to_string();
+        assert!(out.starts_with("current (baseline: baseline)\nsw: +"));
+        assert!(out.ends_with("% (slower)\n"));
+        let report = current.compare(&baseline).with_threshold(100.);
+        assert!(report.regressions().is_empty());
+        assert!(baseline
+            .compare(&current)
+            .to_string()
+            .ends_with("% (faster)\n"));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_benchman_save_load() {
+        let benchman = BenchMan::new("save");
+        drop(benchman.get_stopwatch("b"));
+        benchman.get_stopwatch("a").stop_with_items(10);
+        let _sw = benchman.get_stopwatch("pending");
+        let path = std::env::temp_dir().join(format!("benchman-{}.json", std::process::id()));
+        benchman.save(&path).unwrap();
+        let loaded = BenchMan::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.to_string(), benchman.to_string());
+        assert_eq!(loaded.samples("b"), benchman.samples("b"));
+        assert_eq!(loaded.samples("pending"), Some(vec![]));
+    }
+
+    #[cfg(all(feature = "serde", target_os = "linux"))]
+    #[test]
+    fn test_benchman_save_error() {
+        let benchman = BenchMan::new("save_error");
+        drop(benchman.get_stopwatch("sw"));
+        assert!(benchman.save("/dev/full").is_err());
+    }
+
+    fn block_on<F: Future>(fut: F) -> F::Output {
+        struct ThreadWaker(std::thread::Thread);
+        impl std::task::Wake for ThreadWaker {
+            fn wake(self: Arc<Self>) {
+                self.0.unpark();
+            }
+        }
+        let waker = Arc::new(ThreadWaker(std::thread::current())).into();
+        let mut cx = std::task::Context::from_waker(&waker);
+        let mut fut = Box::pin(fut);
+        loop {
+            match fut.as_mut().poll(&mut cx) {
+                std::task::Poll::Ready(x) => return x,
+                std::task::Poll::Pending => std::thread::park(),
+            }
+        }
+    }
+
+    // Pending once and wakes up after the duration.
+    struct Sleep(Option<Duration>);
+    impl Future for Sleep {
+        type Output = ();
+        fn poll(mut self: Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> std::task::Poll<()> {
+            match self.0.take() {
+                Some(du) => {
+                    let waker = cx.waker().clone();
+                    std::thread::spawn(move || {
+                        std::thread::sleep(du);
+                        waker.wake();
+                    });
+                    std::task::Poll::Pending
+                }
+                None => std::task::Poll::Ready(()),
+            }
+        }
+    }
+
+    #[test]
+    fn test_send_sync() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<BenchMan>();
+        assert_send_sync::<Stopwatch>();
+    }
+
+    #[test]
+    fn test_benchman_measure_async() {
+        let benchman = BenchMan::new("async");
+        let fut = async {
+            Sleep(Some(Duration::from_millis(50))).await;
+            1
+        };
+        assert_eq!(block_on(benchman.measure_async("wall", fut)), 1);
+        let fut = Sleep(Some(Duration::from_millis(50)));
+        block_on(benchman.measure_async_polls("polls", fut));
+        drop(benchman.measure_async("dropped", async {}));
+        let snapshot = benchman.snapshot();
+        assert!(snapshot["wall"].average >= Duration::from_millis(50));
+        assert!(snapshot["polls"].average < Duration::from_millis(50));
+        assert!(!snapshot.contains_key("dropped"));
+    }
+
+    #[test]
+    fn test_benchman_per_thread() {
+        let benchman = BenchMan::new("per_thread");
+        benchman.set_per_thread(true);
+        drop(benchman.get_stopwatch("sw"));
+        let bm = benchman.clone();
+        let id = std::thread::spawn(move || {
+            for _ in 0..2 {
+                drop(bm.get_stopwatch("sw"));
+            }
+            std::thread::current().id()
+        })
+        .join()
+        .unwrap();
+        let by_thread = benchman.by_thread("sw");
+        assert_eq!(by_thread.len(), 2);
+        assert_eq!(by_thread[0].0, std::thread::current().id());
+        assert_eq!(by_thread[0].1.samples, 1);
+        assert_eq!(by_thread[1].0, id);
+        assert_eq!(by_thread[1].1.samples, 2);
+        assert_eq!(benchman.snapshot()["sw"].samples, 3);
+        assert!(benchman
+            .to_string()
+            .contains(&format!("  {:?} (2 samples)", id)));
+    }
+
+    #[test]
+    fn test_benchman_to_markdown() {
+        let benchman = BenchMan::new("markdown");
+        drop(benchman.get_stopwatch("b"));
+        drop(benchman.get_stopwatch("a|x"));
+        let md = benchman.to_markdown();
+        let lines: Vec<&str> = md.lines().collect();
+        assert_eq!(lines.len(), 4);
+        assert_eq!(lines[0], "| tag | samples | mean | p50 | p95 | p99 |");
+        assert!(lines[2].starts_with("| b | 1 | "));
+        assert!(lines[3].starts_with("| a\\|x | 1 | "));
+    }
+
+    #[test]
+    fn test_format_duration() {
+        assert_eq!(format_duration(Duration::ZERO), "0 ns");
+        assert_eq!(format_duration(Duration::from_nanos(999)), "999 ns");
+        assert_eq!(format_duration(Duration::from_nanos(1_234_567)), "1.23 ms");
+        assert_eq!(format_duration(Duration::from_nanos(987_000)), "987 µs");
+        assert_eq!(format_duration(Duration::from_millis(2100)), "2.10 s");
+        assert_eq!(format_duration(Duration::from_nanos(9_999)), "10.0 µs");
+        assert_eq!(format_duration(Duration::from_nanos(99_960)), "100 µs");
+        assert_eq!(format_duration(Duration::from_nanos(999_999)), "1.00 ms");
+        assert_eq!(format_duration(Duration::from_secs(5000)), "5000 s");
+    }
+
+    #[test]
+    fn test_benchman_count() {
+        let benchman = BenchMan::new("count");
+        benchman.set_colored(false);
+        drop(benchman.get_stopwatch("cache_miss"));
+        benchman.count("cache_miss", 1400);
+        benchman.count("cache_miss", 23);
+        assert_eq!(benchman.counter("cache_miss"), Some(1423));
+        assert_eq!(benchman.counter("cache_hit"), None);
+        assert_eq!(benchman.snapshot()["cache_miss"].samples, 1);
+        assert!(benchman
+            .to_string()
+            .ends_with("\ncache_miss: 1423 (total)\n"));
+        benchman.clear_tag("cache_miss");
+        assert_eq!(benchman.sample_count("cache_miss"), 0);
+        assert_eq!(benchman.counter("cache_miss"), Some(1423));
+        benchman.clear_counter("cache_miss");
+        assert_eq!(benchman.counter("cache_miss"), None);
+    }
+
+    #[test]
+    fn test_geometric_mean() {
+        let mut br = BenchResult::new();
+        assert_eq!(br.geometric_mean(), None);
+        br.add_result(Duration::from_millis(1));
+        br.add_result(Duration::from_millis(100));
+        let geomean = br.geometric_mean().unwrap().as_secs_f64();
+        assert!((geomean - 0.01).abs() < 1e-9);
+        br.add_result(Duration::ZERO);
+        assert_eq!(br.geometric_mean(), Some(Duration::ZERO));
+
+        let benchman = BenchMan::new("geomean");
+        assert_eq!(benchman.overall_geomean(), None);
+        drop(benchman.get_stopwatch("a"));
+        drop(benchman.get_stopwatch("b"));
+        assert!(benchman.overall_geomean().is_some());
+    }
+
+    #[test]
+    fn test_benchman_calibrate() {
+        let benchman = BenchMan::new("calibrate");
+        let sw = benchman.get_stopwatch("sw");
+        std::thread::sleep(Duration::from_millis(1));
+        drop(sw);
+        let stats = &benchman.snapshot()["sw"];
+        assert_eq!(stats.average_adjusted(), stats.average);
+        let overhead = benchman.calibrate();
+        assert!(overhead < Duration::from_millis(1));
+        let stats = &benchman.snapshot()["sw"];
+        assert_eq!(stats.average_adjusted(), stats.average - overhead);
+        assert!(!benchman.snapshot().contains_key("calibration"));
+    }
+
+    #[test]
+    fn test_stopwatch_child() {
+        let benchman = BenchMan::new("child");
+        benchman.set_colored(false);
+        let outer = benchman.get_stopwatch("outer");
+        drop(benchman.get_stopwatch("other"));
+        for _ in 0..2 {
+            let inner = outer.child("inner");
+            std::thread::sleep(Duration::from_millis(10));
+            drop(inner.child("leaf"));
+        }
+        std::thread::sleep(Duration::from_millis(10));
+        drop(outer);
+        let out = benchman.to_string();
+        let pos = |s: &str| out.find(s).unwrap();
+        assert!(pos("\nouter (1 samples)\n") < pos("\n  outer/inner (2 samples) "));
+        assert!(pos("\n  outer/inner (2 samples) ") < pos("\n    outer/inner/leaf (2 samples) "));
+        assert!(pos("\n    outer/inner/leaf (2 samples) [") < pos("\n    (unaccounted) "));
+        assert!(pos("\n    (unaccounted) ") < pos("\n  (unaccounted) "));
+        assert!(pos("\n  (unaccounted) ") < pos("% of outer]\n\nother (1 samples)\n"));
+        assert_eq!(benchman.snapshot()["outer/inner"].samples, 2);
+    }
+
+    #[test]
+    fn test_benchman_sample_count() {
+        let benchman = BenchMan::new("sample_count");
+        let sw = benchman.get_stopwatch("sw");
+        assert_eq!(benchman.sample_count("sw"), 0);
+        assert!(!benchman.has_samples("sw"));
+        drop(sw);
+        drop(benchman.get_stopwatch("sw"));
+        assert_eq!(benchman.sample_count("sw"), 2);
+        assert!(benchman.has_samples("sw"));
+        assert_eq!(benchman.sample_count("unknown"), 0);
+    }
+
+    #[test]
+    fn test_benchman_max_samples() {
+        let ms = Duration::from_millis;
+        let benchman = BenchMan::new("max_samples");
+        benchman.set_colored(false);
+        let add = |tag: &str, du| {
+            let mut result_set_writer = benchman.result_set.write().unwrap();
+            result_set_writer.reserve_tag(tag.to_owned());
+            result_set_writer.add_result(tag.to_owned(), du, None);
+        };
+        for i in 1..=5 {
+            add("latest", ms(i));
+        }
+        benchman.set_max_samples("latest", 3, Retention::Latest);
+        assert_eq!(benchman.samples("latest").unwrap(), [ms(3), ms(4), ms(5)]);
+        for i in 6..=7 {
+            add("latest", ms(i));
+        }
+        assert_eq!(benchman.samples("latest").unwrap(), [ms(5), ms(6), ms(7)]);
+        assert!(benchman.to_string().contains("latest (3 of 7 samples)\n"));
+
+        benchman.set_max_samples("reservoir", 10, Retention::Reservoir);
+        for i in 0..1000 {
+            add("reservoir", ms(i));
+        }
+        let samples = benchman.samples("reservoir").unwrap();
+        assert_eq!(samples.len(), 10);
+        assert!(samples.iter().all(|&du| du < ms(1000)));
+
+        // The merged results per thread are capped as well.
+        benchman.set_per_thread(true);
+        let other = BenchMan::new("other");
+        other.set_per_thread(true);
+        for _ in 0..5 {
+            drop(other.get_stopwatch("latest"));
+        }
+        benchman.merge(&other);
+        assert_eq!(benchman.by_thread("latest")[0].1.samples, 3);
+
+        // The merged reservoir keeps the samples in proportion to the samples seen by each side.
+        let other = BenchMan::new("other");
+        for _ in 0..10 {
+            other.add_weighted("reservoir", ms(2000), 1);
+        }
+        benchman.merge(&other);
+        let samples = benchman.samples("reservoir").unwrap();
+        assert_eq!(samples.len(), 10);
+        assert!(samples.iter().filter(|&&du| du == ms(2000)).count() <= 3);
+
+        #[cfg(feature = "serde")]
+        {
+            let path = std::env::temp_dir()
+                .join(format!("benchman-max-samples-{}.json", std::process::id()));
+            benchman.save(&path).unwrap();
+            let loaded = BenchMan::load(&path).unwrap();
+            std::fs::remove_file(&path).unwrap();
+            loaded.set_colored(false);
+            assert!(loaded.to_string().contains("latest (3 of 12 samples)\n"));
+            drop(loaded.get_stopwatch("latest"));
+            assert_eq!(loaded.sample_count("latest"), 3);
+        }
+    }
+
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn test_stopwatch_tracing() {
+        use std::sync::atomic::{AtomicU64, Ordering};
+        use tracing::{span, Event, Metadata, Subscriber};
+
+        static SPANS: AtomicU64 = AtomicU64::new(0);
+        static EVENTS: AtomicU64 = AtomicU64::new(0);
+        struct Counter {
+            spans: bool,
+        }
+        impl Subscriber for Counter {
+            fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+                metadata.target() == "benchman" && (self.spans || metadata.is_event())
+            }
+            fn new_span(&self, _: &span::Attributes<'_>) -> span::Id {
+                span::Id::from_u64(SPANS.fetch_add(1, Ordering::SeqCst) + 1)
+            }
+            fn record(&self, _: &span::Id, _: &span::Record<'_>) {}
+            fn record_follows_from(&self, _: &span::Id, _: &span::Id) {}
+            fn event(&self, _: &Event<'_>) {
+                EVENTS.fetch_add(1, Ordering::SeqCst);
+            }
+            fn enter(&self, _: &span::Id) {}
+            fn exit(&self, _: &span::Id) {}
+        }
+
+        let benchman = BenchMan::new("tracing");
+        tracing::subscriber::with_default(Counter { spans: true }, || {
+            let sw = benchman.get_stopwatch("outer");
+            drop(sw.child("inner"));
+            drop(sw);
+            benchman.get_stopwatch("cancelled").cancel();
+            benchman.calibrate();
+            let mut sw = benchman.get_stopwatch("laps");
+            sw.lap("1");
+            sw.lap("2");
+            drop(sw);
+            benchman.add_weighted("weighted", Duration::from_millis(3), 3);
+        });
+        assert_eq!(SPANS.load(Ordering::SeqCst), 4);
+        assert_eq!(EVENTS.load(Ordering::SeqCst), 6);
+
+        // The events are emitted without the spans
+        // and by a stopwatch created before the subscriber is set.
+        SPANS.store(0, Ordering::SeqCst);
+        EVENTS.store(0, Ordering::SeqCst);
+        let early = benchman.get_stopwatch("early");
+        tracing::subscriber::with_default(Counter { spans: false }, || {
+            drop(early);
+            let mut sw = benchman.get_stopwatch("laps");
+            sw.lap("1");
+            drop(sw);
+        });
+        assert_eq!(SPANS.load(Ordering::SeqCst), 0);
+        assert_eq!(EVENTS.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn test_benchman_add_weighted() {
+        let ms = Duration::from_millis;
+        let benchman = BenchMan::new("weighted");
+        benchman.add_weighted("batch", ms(30), 3);
+        benchman.add_weighted("batch", ms(10), 1);
+        assert_eq!(benchman.sample_count("batch"), 2);
+        assert_eq!(benchman.samples("batch").unwrap(), [ms(10), ms(10)]);
+        benchman.add_weighted("batch", ms(60), 2);
+        let stats = &benchman.snapshot()["batch"];
+        // 10ms x 4 and 30ms x 2
+        assert_eq!(stats.samples, 3);
+        assert_eq!(stats.average, ms(100) / 6);
+        assert_eq!(stats.total(), ms(100));
+        assert_eq!(stats.percentile(66.), ms(10));
+        assert_eq!(stats.percentile(67.), ms(30));
+        assert_eq!(stats.percentile_with(50., PercentileMethod::Linear), ms(10));
+        assert_eq!(stats.histogram(2, HistogramScale::Linear)[0].1, 4);
+        // 3 samples for the degrees of freedom
+        let (lo, hi) = stats.mean_confidence_interval(0.95).unwrap();
+        assert!(lo < ms(5) && hi > ms(28));
+
+        // The total isn't truncated by the division.
+        benchman.add_weighted("ns", Duration::from_nanos(10), 3);
+        assert_eq!(benchman.snapshot()["ns"].total(), Duration::from_nanos(10));
+        // A single batch has no confidence interval.
+        assert_eq!(
+            benchman.snapshot()["ns"].mean_confidence_interval(0.95),
+            None
+        );
+
+        // The warmup and the cap count a batch as a single sample.
+        benchman.set_warmup("warmup", 1);
+        benchman.add_weighted("warmup", ms(30), 3);
+        assert_eq!(benchman.sample_count("warmup"), 0);
+        benchman.add_weighted("warmup", ms(30), 3);
+        assert_eq!(benchman.sample_count("warmup"), 1);
+        benchman.set_max_samples("capped", 1, Retention::Latest);
+        benchman.add_weighted("capped", ms(10), 1);
+        benchman.add_weighted("capped", ms(60), 3);
+        let stats = &benchman.snapshot()["capped"];
+        assert_eq!(stats.samples, 1);
+        assert_eq!(stats.average, ms(20));
+
+        #[cfg(feature = "serde")]
+        {
+            let path =
+                std::env::temp_dir().join(format!("benchman-weighted-{}.json", std::process::id()));
+            benchman.save(&path).unwrap();
+            let loaded = BenchMan::load(&path).unwrap();
+            std::fs::remove_file(&path).unwrap();
+            assert_eq!(loaded.snapshot()["batch"].average, ms(100) / 6);
+            assert_eq!(loaded.to_string(), benchman.to_string());
+        }
+    }
+
+    #[test]
+    fn test_student_t_quantile() {
+        for (v, t) in [(1., 12.7062), (9., 2.2622), (30., 2.0423)] {
+            assert!((student_t_quantile(0.975, v) - t).abs() < 1e-4);
+        }
+        assert!((student_t_quantile(0.995, 4.) - 4.6041).abs() < 1e-4);
+    }
+
+    #[test]
+    fn test_bench_result_mean_confidence_interval() {
+        let mut br = BenchResult::new();
+        br.add_result(Duration::from_millis(10));
+        assert_eq!(br.mean_confidence_interval(0.95), None);
+        for ms in [12, 11, 9, 13, 10, 8, 12, 11, 9] {
+            br.add_result(Duration::from_millis(ms));
+        }
+        // mean = 10.5ms, s = 1.581ms, t(0.975, 9) = 2.262
+        let (lo, hi) = br.mean_confidence_interval(0.95).unwrap();
+        assert!((lo.as_secs_f64() - 0.009369).abs() < 1e-6);
+        assert!((hi.as_secs_f64() - 0.011631).abs() < 1e-6);
+
+        let benchman = BenchMan::new("ci");
+        benchman.set_confidence_interval(Some(0.95));
+        drop(benchman.get_stopwatch("sw"));
+        drop(benchman.get_stopwatch("sw"));
+        assert!(benchman.to_string().contains("\n[95% CI] "));
+        benchman.set_confidence_interval(Some(0.57));
+        assert!(benchman.to_string().contains("\n[57% CI] "));
+        benchman.set_confidence_interval(Some(0.999));
+        assert!(benchman.to_string().contains("\n[99.9% CI] "));
+        assert_eq!(format_level(0.9999), "99.99");
+
+        // The average is printed even if the lower bound is clamped.
+        let benchman = BenchMan::new("ci_clamped");
+        benchman.set_colored(false);
+        benchman.set_confidence_interval(Some(0.99));
+        benchman.add_weighted("sw", Duration::from_nanos(1), 1);
+        benchman.add_weighted("sw", Duration::from_nanos(100), 1);
+        let out = benchman.to_string();
+        assert!(out.contains("\n[ave.] 50 ns\n[99% CI] 50 ns ± "));
+    }
+
+    #[test]
+    fn test_benchman_report() {
+        let benchman = BenchMan::new("report");
+        drop(benchman.get_stopwatch("sw"));
+        benchman.count("counter", 1);
+        assert_eq!(benchman.report(), benchman.to_string());
+    }
+
+    #[test]
+    fn test_benchman_uncolored() {
+        let benchman = BenchMan::new("uncolored");
+        benchman.set_colored(false);
+        drop(benchman.get_stopwatch("sw"));
+        let out = benchman.to_string();
+        assert!(out.starts_with("uncolored\nsw (1 samples)\n"));
+        assert!(!out.contains('\x1b'));
+        assert!(!benchman.slice(["sw"]).to_string().contains('\x1b'));
+    }
+
+    #[test]
+    fn test_bench_result_min_max() {
+        let mut br = BenchResult::new();
+        br.add_result(Duration::from_millis(3));
+        assert_eq!(br.min(), Some(Duration::from_millis(3)));
+        assert_eq!(br.max(), Some(Duration::from_millis(3)));
+        br.add_result(Duration::from_millis(1));
+        br.add_result(Duration::from_millis(8));
+        assert_eq!(br.min(), Some(Duration::from_millis(1)));
+        assert_eq!(br.max(), Some(Duration::from_millis(8)));
+    }
+
+    #[test]
+    fn test_bench_result_stddev() {
+        let mut br = BenchResult::new();
+        for ms in [2, 4, 4, 4, 5, 5, 7, 9] {
+            br.add_result(Duration::from_millis(ms));
+        }
+        let stddev = br.stddev().unwrap().as_secs_f64();
+        assert!((stddev - 0.002).abs() < 1e-9);
+        assert!((br.cv().unwrap() - 0.4).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_bench_result_empty() {
+        let br = BenchResult::new();
+        assert_eq!(br.average(), None);
+        assert_eq!(br.percentile(50.), None);
+        assert_eq!(br.to_string(), "(no samples)\n");
+    }
 }